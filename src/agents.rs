@@ -0,0 +1,331 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet, VecDeque}, sync::Arc};
+use crate::maze::{refuse_hazards, safe_neighbours, safe_position, Direction, Hazard, Maze, Path, Position};
+
+/// How the keys lying in the maze are divided between the agents.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Sharing {
+    /// Every agent picks up every key it steps on for itself.
+    #[default]
+    OWN,
+    /// Every key can be picked up once, by whichever agent the planner gives it to.
+    SHARED,
+}
+
+// Where an agent may not be when: on a field at a tick, or moving between two fields into a tick.
+// Keys given to other agents are forbidden too.
+#[derive(Clone, Default)]
+struct Constraints {
+    fields: HashSet<(Position, usize)>,
+    moves: HashSet<(Position, Position, usize)>,
+    keys: HashSet<Position>,
+}
+
+impl Constraints {
+    fn horizon(&self) -> usize {
+        let fields = self.fields.iter().map(|(_, t)| *t);
+        let moves = self.moves.iter().map(|(_, _, t)| *t);
+        fields.chain(moves).max().unwrap_or(0)
+    }
+}
+
+// A single agent's way out: its moves, where it is at every tick and the keys it picks up.
+#[derive(Clone)]
+struct Route {
+    steps: Vec<Direction>,
+    positions: Vec<Position>,
+    keys: Vec<Position>,
+}
+
+impl Route {
+    // Where the agent is at the tick, or `None` once it has left through an exit.
+    fn at(&self, t: usize) -> Option<Position> {
+        self.positions.get(t).copied()
+    }
+}
+
+// Two agents on a field at a tick, swapping fields into a tick, or picking up the same key.
+#[derive(Clone, Copy)]
+enum Conflict {
+    Field(usize, usize, Position, usize),
+    Swap(usize, usize, Position, Position, usize),
+    Key(usize, usize, Position),
+}
+
+// Field, keys picked up, keys in hand, tick, and the state and move it was reached from.
+type State = (usize, u64, u16, usize, Option<(usize, Direction)>);
+
+// The maze as a graph of field indices, with the bit of every key field and the keys in hand the
+// snare on every field takes.
+struct Board {
+    positions: Vec<Position>,
+    index: HashMap<Position, usize>,
+    moves: Vec<Vec<(Direction, bool, usize)>>,
+    end: Vec<bool>,
+    bits: HashMap<usize, u32>,
+    snares: Vec<u16>,
+}
+
+impl Board {
+    fn new(maze: &Maze) -> Result<Self, String> {
+        let fields = maze.fields();
+        refuse_hazards(fields, "Planning for agents", |h| matches!(h, Hazard::TRAP(_) | Hazard::BRIDGE))?;
+        let positions: Vec<Position> = fields.iter().map(|f| safe_position(Arc::clone(f))).collect();
+        let index: HashMap<Position, usize> = positions.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+        let moves = fields.iter().map(|f| {
+            safe_neighbours(Arc::clone(f)).into_iter()
+                .filter_map(|(d, doors, f2)| index.get(&safe_position(f2)).map(|to| (d, doors, *to)))
+                .collect()
+        }).collect();
+        let end = fields.iter().map(|f| f.lock().unwrap().is_end()).collect();
+        let keys: Vec<usize> = (0..fields.len()).filter(|i| fields[*i].lock().unwrap().has_key()).collect();
+        if keys.len() > 64 {
+            return Err(format!("At most 64 keys can be tracked, the maze has {}.", keys.len()));
+        }
+        let bits = keys.into_iter().enumerate().map(|(b, i)| (i, b as u32)).collect();
+        let snares = fields.iter().map(|f| match f.lock().unwrap().get_hazard() {
+            Some(Hazard::SNARE(n)) => n,
+            _ => 0,
+        }).collect();
+        Ok(Board { positions, index, moves, end, bits, snares })
+    }
+
+    // Shortest route out from `start` keeping to the constraints: breadth-first in time, where after
+    // the last constrained tick waiting can't help any more and the time stops mattering.
+    fn route(&self, start: usize, c: &Constraints) -> Option<Route> {
+        let horizon = c.horizon() + 1;
+        let pick = |i: usize, keys: u64, hand: u16| {
+            let hand = hand.saturating_sub(self.snares[i]);
+            match self.bits.get(&i) {
+                Some(b) if keys & (1 << b) == 0 && !c.keys.contains(&self.positions[i]) => (keys | 1 << b, hand + 1),
+                _ => (keys, hand),
+            }
+        };
+        if c.fields.contains(&(self.positions[start], 0)) {
+            return None;
+        }
+        let (keys, hand) = pick(start, 0, 0);
+        let mut states: Vec<State> = vec![(start, keys, hand, 0, None)];
+        let mut seen = HashSet::from([(start, keys, hand, 0)]);
+        let mut queue = VecDeque::from([0]);
+        while let Some(s) = queue.pop_front() {
+            let (i, keys, hand, t, _) = states[s];
+            if self.end[i] {
+                return Some(self.unwind(&states, s));
+            }
+            let wait = std::iter::once((Direction::WAIT, false, i));
+            for (d, doors, j) in wait.chain(self.moves[i].iter().copied()) {
+                if doors && hand == 0 {
+                    continue;
+                }
+                let (from, to) = (self.positions[i], self.positions[j]);
+                if c.fields.contains(&(to, t + 1)) || c.moves.contains(&(from, to, t + 1)) {
+                    continue;
+                }
+                let (keys, hand) = pick(j, keys, if doors { hand - 1 } else { hand });
+                if seen.insert((j, keys, hand, (t + 1).min(horizon))) {
+                    states.push((j, keys, hand, t + 1, Some((s, d))));
+                    queue.push_back(states.len() - 1);
+                }
+            }
+        }
+        None
+    }
+
+    fn unwind(&self, states: &[State], last: usize) -> Route {
+        let mut chain = vec![last];
+        while let Some((prev, _)) = states[*chain.last().unwrap()].4 {
+            chain.push(prev);
+        }
+        chain.reverse();
+        let steps = chain.iter().filter_map(|s| states[*s].4.map(|(_, d)| d)).collect();
+        let positions = chain.iter().map(|s| self.positions[states[*s].0]).collect();
+        let mut keys = Vec::new();
+        for pair in chain.windows(2) {
+            let (before, after) = (states[pair[0]].1, states[pair[1]].1);
+            if after != before {
+                keys.push(self.positions[states[pair[1]].0]);
+            }
+        }
+        if states[chain[0]].1 != 0 {
+            keys.push(self.positions[states[chain[0]].0]);
+        }
+        Route { steps, positions, keys }
+    }
+}
+
+// The first time two routes get in each other's way, or two of them pick up the same key.
+fn conflict(routes: &[Route], sharing: Sharing) -> Option<Conflict> {
+    let last = routes.iter().map(|r| r.positions.len()).max().unwrap_or(0);
+    for t in 0..last {
+        for a in 0..routes.len() {
+            for b in a + 1..routes.len() {
+                let (Some(pa), Some(pb)) = (routes[a].at(t), routes[b].at(t)) else {
+                    continue;
+                };
+                if pa == pb {
+                    return Some(Conflict::Field(a, b, pa, t));
+                }
+                if let (Some(na), Some(nb)) = (routes[a].at(t + 1), routes[b].at(t + 1)) {
+                    if na == pb && nb == pa {
+                        return Some(Conflict::Swap(a, b, pa, na, t + 1));
+                    }
+                }
+            }
+        }
+    }
+    if sharing == Sharing::SHARED {
+        for a in 0..routes.len() {
+            for b in a + 1..routes.len() {
+                if let Some(k) = routes[a].keys.iter().find(|k| routes[b].keys.contains(k)) {
+                    return Some(Conflict::Key(a, b, *k));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Conflict-based search for ways out for agents starting on the fields `starts`, one tick per move,
+/// such that no two agents are ever on the same field at the same tick or swap fields with each other.
+///
+/// Keys are picked up and spent on doors as in the key-aware search, for every agent on its own or
+/// with the keys divided between them as `sharing` says, but unlike there a route may come back
+/// through a transition it took before, as it may have to step aside and return to let another
+/// agent pass. An agent leaves the maze when it reaches an exit and
+/// is out of the others' way from then on. Paths are as long as the tick the agent leaves on,
+/// `Direction::WAIT` standing for the ticks it waits, and the sum of their costs is the smallest
+/// possible. Returns `None` if the agents can't all get out, or the search gives up after
+/// `limit` conflicts. Snares take keys in hand as in the key-aware search, but traps and bridges
+/// aren't kept to, so it returns an error for a maze with any of them, as it does for one with more
+/// than 64 keys.
+pub fn plan(maze: &Maze, starts: &[Position], sharing: Sharing, limit: usize) -> Result<Option<Vec<Path>>, String> {
+    let board = Board::new(maze)?;
+    let Some(starts) = starts.iter().map(|p| board.index.get(p).copied()).collect::<Option<Vec<usize>>>() else {
+        return Ok(None);
+    };
+    let constraints = vec![Constraints::default(); starts.len()];
+    let Some(routes) = starts.iter().map(|s| board.route(*s, &Constraints::default())).collect::<Option<Vec<Route>>>() else {
+        return Ok(None);
+    };
+    let cost = |routes: &[Route]| routes.iter().map(|r| r.steps.len()).sum::<usize>();
+
+    // Nodes of the constraint tree, explored cheapest first.
+    let mut queue = BinaryHeap::from([Reverse((cost(&routes), 0))]);
+    let mut nodes: Vec<(Vec<Constraints>, Vec<Route>)> = vec![(constraints, routes)];
+    let mut resolved = 0;
+    while let Some(Reverse((_, n))) = queue.pop() {
+        let Some(found) = conflict(&nodes[n].1, sharing) else {
+            let routes = std::mem::take(&mut nodes[n].1);
+            return Ok(Some(routes.into_iter().map(|r| Path::from_steps(r.steps)).collect()));
+        };
+        resolved += 1;
+        if resolved > limit {
+            return Ok(None);
+        }
+        // Either of the two agents has to give way.
+        for first in [true, false] {
+            let mut constraints = nodes[n].0.clone();
+            let agent = match found {
+                Conflict::Field(a, b, p, t) => {
+                    let agent = if first { a } else { b };
+                    constraints[agent].fields.insert((p, t));
+                    agent
+                },
+                Conflict::Swap(a, b, pa, pb, t) => {
+                    let (agent, from, to) = if first { (a, pa, pb) } else { (b, pb, pa) };
+                    constraints[agent].moves.insert((from, to, t));
+                    agent
+                },
+                Conflict::Key(a, b, k) => {
+                    let agent = if first { a } else { b };
+                    constraints[agent].keys.insert(k);
+                    agent
+                },
+            };
+            let Some(route) = board.route(starts[agent], &constraints[agent]) else {
+                continue;
+            };
+            let mut routes = nodes[n].1.clone();
+            routes[agent] = route;
+            queue.push(Reverse((cost(&routes), nodes.len())));
+            nodes.push((constraints, routes));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{maze::{Direction, Maze, Path}, reader::read_maze};
+
+    use super::{conflict, plan, Conflict, Route, Sharing};
+
+    // Where every agent is at every tick, following their paths from the starts.
+    fn positions(maze: &Maze, starts: &[(u8, u8, u8)], paths: &[Path]) -> Vec<Vec<(u8, u8, u8)>> {
+        starts.iter().zip(paths).map(|(s, p)| {
+            let mut at = vec![*s];
+            for d in p.steps() {
+                let here = *at.last().unwrap();
+                let next = if d == Direction::WAIT {
+                    here
+                } else {
+                    let t = maze.get(here).unwrap().lock().unwrap().get_transition(d).unwrap();
+                    let f = t.lock().unwrap().get_field2();
+                    let p = f.lock().unwrap().position();
+                    p
+                };
+                at.push(next);
+            }
+            at
+        }).collect()
+    }
+
+    #[test]
+    fn no_swapping() {
+        let route = |positions: &[(u8, u8, u8)]| Route { steps: vec![Direction::EAST; positions.len() - 1], positions: positions.to_vec(), keys: Vec::new() };
+        let routes = [route(&[(0, 0, 0), (1, 0, 0), (2, 0, 0)]), route(&[(2, 0, 0), (1, 0, 0)])];
+        assert!(matches!(conflict(&routes, Sharing::OWN), Some(Conflict::Field(0, 1, (1, 0, 0), 1))));
+        let routes = [route(&[(0, 0, 0), (1, 0, 0)]), route(&[(1, 0, 0), (0, 0, 0)])];
+        assert!(matches!(conflict(&routes, Sharing::OWN), Some(Conflict::Swap(0, 1, (0, 0, 0), (1, 0, 0), 1))));
+        // Following right behind is fine, and so is stepping where an agent that left was.
+        let routes = [route(&[(0, 0, 0), (1, 0, 0), (2, 0, 0)]), route(&[(1, 0, 0), (2, 0, 0)])];
+        assert!(conflict(&routes, Sharing::OWN).is_none());
+    }
+
+    #[test]
+    fn take_turns() {
+        // Both agents need to go through (1,0) to the exit at (2,0).
+        //             (1,1)
+        //               |
+        // (0,0) --- (1,0) --- (2,0) end
+        let maze = read_maze("SQUARE 3 2\n0100 0000 0000\n1101 0000 0000\n1000 0000 0011\n0000 0000 0000\n0010 0000 0000\n0000 0000 0000\n").unwrap();
+        let starts = [(0, 0, 0), (1, 1, 0)];
+        let paths = plan(&maze, &starts, Sharing::OWN, 100).unwrap().unwrap();
+        assert_eq!(paths.iter().map(|p| p.cost()).sum::<usize>(), 2 + 3);
+        assert!(paths.iter().any(|p| p.steps().contains(&Direction::WAIT)));
+        let at = positions(&maze, &starts, &paths);
+        assert!(at[0].iter().zip(&at[1]).all(|(a, b)| a != b));
+        // Agents can't start on the same field.
+        assert!(plan(&maze, &[(0, 0, 0), (0, 0, 0)], Sharing::OWN, 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn shared_keys() {
+        // Keys at (0,0) and (0,1), and doors on the way to the exit.
+        // (0,0) k --- (1,0) -|- (2,0) end
+        //   |
+        // (0,1) k
+        let content = "SQUARE 3 2\n0101 0000 1100\n1100 0100 0000\n1000 1000 0011\n0010 0000 1100\n0000 0000 0000\n0000 0000 0000\n";
+        let starts = [(0, 0, 0), (0, 1, 0)];
+        let maze = read_maze(content).unwrap();
+        let own = plan(&maze, &starts, Sharing::OWN, 100).unwrap().unwrap();
+        let shared = plan(&maze, &starts, Sharing::SHARED, 100).unwrap().unwrap();
+        assert_eq!(own.iter().map(|p| p.cost()).sum::<usize>(), 2 + 3);
+        assert_eq!(shared.iter().map(|p| p.cost()).sum::<usize>(), 2 + 3);
+
+        // With a single key between them one of the agents stays behind.
+        let maze = read_maze(&content.replacen("0010 0000 1100", "0010 0000 0000", 1)).unwrap();
+        assert!(plan(&maze, &starts, Sharing::OWN, 100).unwrap().is_some());
+        assert!(plan(&maze, &starts, Sharing::SHARED, 100).unwrap().is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{collections::HashMap, sync::Arc};
+use crate::maze::{safe_hazard, safe_incoming, safe_neighbours, safe_position, Direction, Field, Hazard, Path, Position};
+
+// One side of the search: how far every field it reached is from where the side started, and the
+// move that got it there (towards the start for the forward side, towards the target for the backward one).
+struct Side {
+    distance: HashMap<Position, usize>,
+    via: HashMap<Position, (Position, Direction)>,
+    frontier: Vec<Field>,
+}
+
+impl Side {
+    fn new(from: &[Field]) -> Self {
+        let distance = from.iter().map(|f| (safe_position(Arc::clone(f)), 0)).collect();
+        Side { distance, via: HashMap::new(), frontier: from.to_vec() }
+    }
+}
+
+/// Shortest path from `f1` to the nearest of `ends` found by searching from the start and the exits
+/// at once, for mazes where keys don't matter.
+///
+/// Returns `None` as soon as the search runs into doors, as only the key-aware search can tell
+/// whether they can be opened, or into traps, as it counts every move the same, and `Some(None)`
+/// if no route leads from `f1` to any of `ends`. Bridges don't matter, as a shortest path never
+/// steps on a field twice, and neither do snares, with no doors to spend keys on.
+/// Every transition the search looks at until the two sides meet is free, so no shorter path
+/// through doors is missed. Both sides only look at the fields next to the ones they reached, the
+/// backward one through the transitions leading into them.
+pub fn doorless_path(f1: Field, ends: &[Field]) -> Option<Option<Path>> {
+    let targets: Vec<Field> = ends.iter().filter(|f| safe_hazard(Arc::clone(f)) != Some(Hazard::FORBIDDEN)).cloned().collect();
+    if targets.iter().any(|f| matches!(safe_hazard(Arc::clone(f)), Some(Hazard::TRAP(_)))) {
+        return None;
+    }
+    if targets.is_empty() {
+        return Some(None);
+    }
+    let start = safe_position(Arc::clone(&f1));
+    if targets.iter().any(|f| safe_position(Arc::clone(f)) == start) {
+        return Some(Some(Path::from_steps(Vec::new())));
+    }
+
+    let mut forward = Side::new(&[f1]);
+    let mut backward = Side::new(&targets);
+    while !forward.frontier.is_empty() && !backward.frontier.is_empty() {
+        // Grow the smaller side by a whole layer, remembering the best place the sides meet.
+        let grow_forward = forward.frontier.len() <= backward.frontier.len();
+        let (this, other) = if grow_forward { (&mut forward, &backward) } else { (&mut backward, &forward) };
+        let mut meeting: Option<(Position, usize)> = None;
+        let mut next = Vec::new();
+        for f in std::mem::take(&mut this.frontier) {
+            let u = safe_position(Arc::clone(&f));
+            let moves = if grow_forward { safe_neighbours(f) } else { safe_incoming(f) };
+            for (direction, doors, g) in moves {
+                if doors || matches!(safe_hazard(Arc::clone(&g)), Some(Hazard::TRAP(_))) {
+                    return None;
+                }
+                let v = safe_position(Arc::clone(&g));
+                if this.distance.contains_key(&v) {
+                    continue;
+                }
+                let d1 = this.distance[&u] + 1;
+                this.distance.insert(v, d1);
+                this.via.insert(v, (u, direction));
+                next.push(g);
+                if let Some(d2) = other.distance.get(&v) {
+                    if meeting.is_none_or(|(_, best)| d1 + d2 < best) {
+                        meeting = Some((v, d1 + d2));
+                    }
+                }
+            }
+        }
+        this.frontier = next;
+        if let Some((m, _)) = meeting {
+            return Some(Some(join(&forward, &backward, m)));
+        }
+    }
+    Some(None)
+}
+
+// Path through the field `m` both sides reached: back to the start along the forward side and on
+// to an exit along the backward one.
+fn join(forward: &Side, backward: &Side, m: Position) -> Path {
+    let mut steps = Vec::new();
+    let mut curr = m;
+    while let Some((prev, d)) = forward.via.get(&curr) {
+        steps.push(*d);
+        curr = *prev;
+    }
+    steps.reverse();
+    let mut curr = m;
+    while let Some((next, d)) = backward.via.get(&curr) {
+        steps.push(*d);
+        curr = *next;
+    }
+    Path::from_steps(steps)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{fixtures::open_grid, maze::{has_path_rules, Direction, Rules}, reader::read_maze};
+
+    use super::doorless_path;
+
+    #[test]
+    fn meets_in_the_middle() {
+        let maze = read_maze(&open_grid(30, 20, (29, 19), &[])).unwrap();
+        let p = doorless_path(maze.start(), &maze.ends()).unwrap().unwrap();
+        assert_eq!(p.cost(), 29 + 19);
+
+        // Doors anywhere near the way make the search give up, and the key-aware one takes over.
+        let maze = read_maze(&open_grid(4, 3, (3, 2), &[(1, 1)])).unwrap();
+        assert!(doorless_path(maze.start(), &maze.ends()).is_none());
+        let rules = Rules { bidirectional: true, ..Rules::default() };
+        assert_eq!(has_path_rules(maze.start(), Arc::clone(&maze.ends()[0]), rules).unwrap().cost(), 5);
+    }
+
+    #[test]
+    fn walled_off() {
+        let maze = read_maze("SQUARE 3 1\n0100 0000 0000\n1000 0000 0000\n0000 0000 0011\n").unwrap();
+        assert!(doorless_path(maze.start(), &maze.ends()).unwrap().is_none());
+    }
+
+    #[test]
+    fn follows_changes() {
+        // (0,0) --- (1,0) --- (2,0) end
+        let mut maze = read_maze("SQUARE 3 1\n0100 0000 0000\n1100 0000 0000\n1000 0000 0011\n").unwrap();
+        assert_eq!(doorless_path(maze.start(), &maze.ends()).unwrap().unwrap().cost(), 2);
+        assert!(maze.remove_transition((1, 0, 0), Direction::EAST));
+        assert!(doorless_path(maze.start(), &maze.ends()).unwrap().is_none());
+
+        // The way east from (0,0) now leads straight to the exit, and no longer into (1,0).
+        assert!(maze.add_transition((0, 0, 0), Direction::EAST, (2, 0, 0), false));
+        assert_eq!(doorless_path(maze.start(), &maze.ends()).unwrap().unwrap().to_string(), "EAST");
+        let middle = maze.get((1, 0, 0)).unwrap();
+        assert!(doorless_path(maze.start(), &[middle]).unwrap().is_none());
+    }
+}
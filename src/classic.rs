@@ -0,0 +1,200 @@
+use core::fmt;
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet, VecDeque}, sync::Arc, time::{Duration, Instant}};
+use crate::{explore::{walk, Strategy}, maze::{safe_neighbours, safe_position, Direction, Hazard, Maze, Path, Position, Rules}};
+
+/// Well-known ways of solving a maze, to compare with the key-aware search.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// Walks with the right hand on the wall.
+    RIGHT,
+    /// Walks with the left hand on the wall.
+    LEFT,
+    /// Walks north, following walls round obstacles until the turns cancel out.
+    PLEDGE,
+    /// Walks marking the ways taken and never takes one a third time.
+    TREMAUX,
+    /// Looks at the whole maze, filling in dead ends until only the ways through are left.
+    FILLING,
+}
+
+pub const ALGORITHMS: [Algorithm; 5] = [Algorithm::RIGHT, Algorithm::LEFT, Algorithm::PLEDGE, Algorithm::TREMAUX, Algorithm::FILLING];
+
+/// How much work an algorithm took.
+pub struct Report {
+    /// Moves walked, none for algorithms that don't walk.
+    pub steps: usize,
+    /// Number of different fields stepped on or searched.
+    pub visited: usize,
+    /// Number of fields filled in as dead ends.
+    pub filled: usize,
+    pub duration: Duration,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Steps walked: {}", self.steps)?;
+        writeln!(f, "Fields visited: {}", self.visited)?;
+        writeln!(f, "Dead ends filled: {}", self.filled)?;
+        writeln!(f, "in: {:?}", self.duration)
+    }
+}
+
+/// Solves the maze with `algorithm`, starting from its first field.
+///
+/// The walking algorithms pick up keys and open doors as in `play::Game`, and their path is the
+/// whole walk, detours included, and keep to the hazards as it does. Dead-end filling knows nothing
+/// about keys and takes doors for walls, but waits out the traps on its path.
+pub fn solve(maze: &Maze, algorithm: Algorithm) -> (Option<Path>, Report) {
+    let start = Instant::now();
+    let strategy = match algorithm {
+        Algorithm::RIGHT => Strategy::RIGHT,
+        Algorithm::LEFT => Strategy::LEFT,
+        Algorithm::PLEDGE => Strategy::PLEDGE,
+        Algorithm::TREMAUX => Strategy::TREMAUX,
+        Algorithm::FILLING => {
+            let (p, visited, filled) = dead_end_filling(maze);
+            return (p, Report { steps: 0, visited, filled, duration: start.elapsed() });
+        },
+    };
+    let (game, visited) = walk(maze, strategy, Rules { grid: maze.grid(), ..Rules::default() });
+    let p = game.is_won().then(|| game.path());
+    let steps = game.moves();
+    (p, Report { steps, visited, filled: 0, duration: start.elapsed() })
+}
+
+// Fills in fields that are neither the start nor an exit and touch at most one field that isn't
+// filled, until there are none left, then searches what is left for the shortest way out, a step
+// onto a trap costing its ticks too.
+fn dead_end_filling(maze: &Maze) -> (Option<Path>, usize, usize) {
+    let fields = maze.fields();
+    let index: HashMap<Position, usize> = fields.iter().enumerate().map(|(i, f)| (safe_position(Arc::clone(f)), i)).collect();
+    let moves: Vec<Vec<(Direction, usize)>> = fields.iter().map(|f| {
+        safe_neighbours(Arc::clone(f)).into_iter()
+            .filter(|(_, doors, _)| !doors)
+            .filter_map(|(d, _, f2)| index.get(&safe_position(f2)).map(|to| (d, *to)))
+            .collect()
+    }).collect();
+    // Fields joined to every field by a transition either way.
+    let mut touching: Vec<HashSet<usize>> = vec![HashSet::new(); fields.len()];
+    for (i, m) in moves.iter().enumerate() {
+        for (_, j) in m {
+            if i != *j {
+                touching[i].insert(*j);
+                touching[*j].insert(i);
+            }
+        }
+    }
+    let end: Vec<bool> = fields.iter().map(|f| f.lock().unwrap().is_end()).collect();
+    let ticks: Vec<usize> = fields.iter().map(|f| match f.lock().unwrap().get_hazard() {
+        Some(Hazard::TRAP(n)) => usize::from(n),
+        _ => 0,
+    }).collect();
+    let mut filled = vec![false; fields.len()];
+    let mut queue: VecDeque<usize> = (1..fields.len()).filter(|i| !end[*i] && touching[*i].len() <= 1).collect();
+    let mut count = 0;
+    while let Some(i) = queue.pop_front() {
+        if filled[i] {
+            continue;
+        }
+        filled[i] = true;
+        count += 1;
+        for j in std::mem::take(&mut touching[i]) {
+            touching[j].remove(&i);
+            if j != 0 && !end[j] && !filled[j] && touching[j].len() <= 1 {
+                queue.push_back(j);
+            }
+        }
+    }
+
+    let mut via: HashMap<usize, (usize, Option<(usize, Direction)>)> = HashMap::from([(0, (0, None))]);
+    let mut heap = BinaryHeap::from([Reverse((0, 0))]);
+    while let Some(Reverse((cost, u))) = heap.pop() {
+        if cost > via[&u].0 {
+            continue;
+        }
+        if end[u] {
+            let mut steps = Vec::new();
+            let mut curr = u;
+            while let Some((_, Some((prev, d)))) = via.get(&curr) {
+                steps.extend(std::iter::repeat_n(Direction::WAIT, ticks[curr]));
+                steps.push(*d);
+                curr = *prev;
+            }
+            steps.reverse();
+            return (Some(Path::from_steps(steps)), via.len(), count);
+        }
+        for (d, v) in &moves[u] {
+            let next = cost + 1 + ticks[*v];
+            if !filled[*v] && via.get(v).is_none_or(|(c, _)| next < *c) {
+                via.insert(*v, (next, Some((u, *d))));
+                heap.push(Reverse((next, *v)));
+            }
+        }
+    }
+    (None, via.len(), count)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixtures::{follow, open_grid}, maze::{Direction, Hazard, Maze}, reader::read_maze};
+
+    use super::{solve, Algorithm, ALGORITHMS};
+
+    // A corridor along the top row to the exit in the top right corner, with a dead end two fields
+    // long hanging from every field of it but the last.
+    fn comb() -> Maze {
+        let mut content = String::from("SQUARE 5 3\n");
+        for y in 0..3 {
+            for x in 0..5 {
+                let open = match y {
+                    0 => [x > 0, x < 4, false, x < 4],
+                    1 => [false, false, x < 4, x < 4],
+                    _ => [false, false, x < 4, false],
+                };
+                let flags = if (x, y) == (4, 0) { "0011" } else { "0000" };
+                content.push_str(&format!("{} 0000 {}\n", open.map(|o| if o { '1' } else { '0' }).iter().collect::<String>(), flags));
+            }
+        }
+        read_maze(&content).unwrap()
+    }
+
+    #[test]
+    fn side_by_side() {
+        let maze = comb();
+        for algorithm in ALGORITHMS {
+            let (p, report) = solve(&maze, algorithm);
+            let p = p.unwrap_or_else(|| panic!("{:?} found no path", algorithm));
+            assert_eq!(follow(&maze.start(), &p.steps()), (4, 0, 0));
+            assert!(p.cost() >= 4);
+            assert_eq!(report.steps, if algorithm == Algorithm::FILLING { 0 } else { p.cost() });
+        }
+        let (p, report) = solve(&maze, Algorithm::FILLING);
+        assert_eq!(p.unwrap().to_string(), "EAST -> EAST -> EAST -> EAST");
+        // The eight fields hanging from the corridor and the two walled off under the exit.
+        assert_eq!(report.filled, 10);
+        assert_eq!(report.visited, 5);
+        // Keeping to the right means going down every dead end passed, all but the one under the start.
+        let (p, _) = solve(&maze, Algorithm::RIGHT);
+        assert_eq!(p.unwrap().cost(), 4 + 3 * 4);
+
+        // A trap on the corridor holds every path up.
+        let mut maze = comb();
+        assert!(maze.set_hazard((2, 0, 0), Some(Hazard::TRAP(2))));
+        let (p, _) = solve(&maze, Algorithm::FILLING);
+        assert_eq!(p.unwrap().to_string(), "EAST -> EAST -> WAIT -> WAIT -> EAST -> EAST");
+        let (p, _) = solve(&maze, Algorithm::TREMAUX);
+        assert!(p.unwrap().steps().contains(&Direction::WAIT));
+    }
+
+    #[test]
+    fn open_field() {
+        // An open field with the exit in the top right corner, where the right hand only finds a circle.
+        let maze = read_maze(&open_grid(5, 5, (4, 0), &[])).unwrap();
+        assert!(solve(&maze, Algorithm::RIGHT).0.is_none());
+        for algorithm in [Algorithm::LEFT, Algorithm::PLEDGE, Algorithm::TREMAUX, Algorithm::FILLING] {
+            let p = solve(&maze, algorithm).0.unwrap_or_else(|| panic!("{:?} found no path", algorithm));
+            assert_eq!(follow(&maze.start(), &p.steps()), (4, 0, 0));
+        }
+        assert_eq!(solve(&maze, Algorithm::FILLING).1.filled, 0);
+    }
+}
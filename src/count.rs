@@ -0,0 +1,315 @@
+use std::collections::{HashMap, VecDeque};
+use crate::{graph::{Graph, KeyStates}, maze::Field};
+
+// What a state on the frontier is to a partly built route: not on it, inside it, or the first or
+// last state of a stretch of it, together with the state at the other end of the stretch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Slot {
+    Free,
+    Inside,
+    First(usize),
+    Last(usize),
+}
+
+// Partly built routes that look the same to the moves still to come, and how many there are of each.
+type Configs = HashMap<Vec<Slot>, u128>;
+
+fn add(configs: &mut Configs, slots: Vec<Slot>, n: u128) {
+    let c = configs.entry(slots).or_insert(0);
+    *c = c.saturating_add(n);
+}
+
+fn slot(frontier: &[usize], s: usize) -> Option<usize> {
+    frontier.iter().position(|f| *f == s)
+}
+
+// Takes the move `from` -> `to` into a partly built route, joining the stretch that ends in `from` with
+// the one that starts in `to`. Returns the first and last state of the joined stretch, or `None` if a
+// state would be left or entered twice, or the stretch would close into a loop.
+fn join(slots: &mut [Slot], frontier: &[usize], from: usize, to: usize) -> Option<(usize, usize)> {
+    let (i, j) = (slot(frontier, from)?, slot(frontier, to)?);
+    let first = match slots[i] {
+        Slot::Free => from,
+        Slot::Last(first) => first,
+        _ => return None,
+    };
+    let last = match slots[j] {
+        Slot::Free => to,
+        Slot::First(last) => last,
+        _ => return None,
+    };
+    if first == to {
+        return None;
+    }
+    slots[i] = if slots[i] == Slot::Free { Slot::First(last) } else { Slot::Inside };
+    slots[j] = if slots[j] == Slot::Free { Slot::Last(first) } else { Slot::Inside };
+    if let Some(k) = slot(frontier, first).filter(|_| first != from) {
+        slots[k] = Slot::First(last);
+    }
+    if let Some(k) = slot(frontier, last).filter(|_| last != to) {
+        slots[k] = Slot::Last(first);
+    }
+    Some((first, last))
+}
+
+struct Counter {
+    states: KeyStates,
+    ends: Vec<bool>,
+    // Whether an exit can be reached from the state at all, to leave hopeless moves out early.
+    useful: Vec<bool>,
+    // Strongly connected component of every state. A route that leaves a component never comes back,
+    // so the routes through a component can be counted on their own for every state it is entered on.
+    component: Vec<usize>,
+    // Routes from a state that was just entered from another component, or from the start.
+    entered: Vec<Option<u128>>,
+}
+
+impl Counter {
+    // Ways a route that got to `s` can end by leaving its component: stopping on it if it is an exit,
+    // or moving on into another component.
+    fn leave(&self, s: usize) -> u128 {
+        if self.ends[s] {
+            return 1;
+        }
+        self.states.moves(s).iter()
+            .filter(|(_, to)| self.component[*to] != self.component[s])
+            .filter_map(|(_, to)| self.entered[*to])
+            .fold(0, u128::saturating_add)
+    }
+
+    // Number of routes from `start` that go through its component without visiting a state twice and
+    // then leave it. They are built a move at a time, in the order the states are first reached, and
+    // only the states with moves on both sides of the current one, the frontier, tell partly built
+    // routes apart, so the work grows with the width of the component rather than with the routes.
+    fn count(&self, start: usize) -> u128 {
+        let c = self.component[start];
+        let mut order = vec![start];
+        let mut position = HashMap::from([(start, 0)]);
+        let mut edges: Vec<(usize, usize, u128)> = Vec::new();
+        let mut k = 0;
+        while k < order.len() {
+            let s = order[k];
+            k += 1;
+            if self.ends[s] {
+                continue;
+            }
+            for (_, to) in self.states.moves(s) {
+                if self.component[*to] != c || !self.useful[*to] || *to == s || *to == start {
+                    continue;
+                }
+                if !position.contains_key(to) {
+                    position.insert(*to, order.len());
+                    order.push(*to);
+                }
+                edges.push((s, *to, 1));
+            }
+        }
+        // Every way of leaving the component is a move to a sink that all routes end on.
+        let sink = usize::MAX;
+        position.insert(sink, order.len());
+        edges.extend(order.iter().map(|s| (*s, sink, self.leave(*s))).filter(|(_, _, n)| *n > 0));
+        edges.sort_by_key(|(s, to, _)| (position[s].max(position[to]), position[s].min(position[to])));
+        let (mut first_edge, mut last_edge) = (HashMap::new(), HashMap::new());
+        for (e, (s, to, _)) in edges.iter().enumerate() {
+            for v in [*s, *to] {
+                first_edge.entry(v).or_insert(e);
+                last_edge.insert(v, e);
+            }
+        }
+
+        let mut frontier: Vec<usize> = Vec::new();
+        let mut configs: Configs = HashMap::from([(Vec::new(), 1)]);
+        let mut total: u128 = 0;
+        for (e, (from, to, weight)) in edges.into_iter().enumerate() {
+            for v in [from, to] {
+                if first_edge[&v] == e {
+                    frontier.push(v);
+                    configs = configs.into_iter().map(|(mut slots, n)| {
+                        slots.push(Slot::Free);
+                        (slots, n)
+                    }).collect();
+                }
+            }
+            let mut next: Configs = HashMap::with_capacity(configs.len());
+            for (slots, n) in configs {
+                let mut taken = slots.clone();
+                match join(&mut taken, &frontier, from, to) {
+                    Some((first, last)) if first == start && last == sink => {
+                        // A whole route, unless another stretch was started that it would leave behind.
+                        let done = taken.iter().zip(&frontier)
+                            .all(|(slot, s)| *s == start || *s == sink || matches!(slot, Slot::Free | Slot::Inside));
+                        if done {
+                            total = total.saturating_add(n.saturating_mul(weight));
+                        }
+                    },
+                    Some(_) => add(&mut next, taken, n),
+                    None => {},
+                }
+                add(&mut next, slots, n);
+            }
+            configs = next;
+            // A state leaving the frontier can't be the end of a stretch that still has to grow, and the
+            // start has to be on the route by then.
+            for v in [to, from] {
+                let Some(i) = slot(&frontier, v).filter(|_| last_edge[&v] == e) else {
+                    continue;
+                };
+                frontier.remove(i);
+                let mut next: Configs = HashMap::with_capacity(configs.len());
+                for (mut slots, n) in configs {
+                    let keep = match slots.remove(i) {
+                        Slot::Free => v != start && v != sink,
+                        Slot::Inside => true,
+                        Slot::First(_) => v == start,
+                        Slot::Last(_) => v == sink,
+                    };
+                    if keep {
+                        add(&mut next, slots, n);
+                    }
+                }
+                configs = next;
+            }
+        }
+        total
+    }
+}
+
+// Kosaraju's algorithm: number the strongly connected components of the key states.
+fn components(states: &KeyStates, reverse: &[Vec<usize>]) -> Vec<usize> {
+    let n = states.len();
+    let mut order = Vec::with_capacity(n);
+    let mut seen = vec![false; n];
+    for root in 0..n {
+        if seen[root] {
+            continue;
+        }
+        seen[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((s, m)) = stack.pop() {
+            match states.moves(s).get(m) {
+                Some((_, to)) => {
+                    stack.push((s, m + 1));
+                    if !seen[*to] {
+                        seen[*to] = true;
+                        stack.push((*to, 0));
+                    }
+                },
+                None => order.push(s),
+            }
+        }
+    }
+    let mut component = vec![usize::MAX; n];
+    let mut c = 0;
+    for root in order.into_iter().rev() {
+        if component[root] != usize::MAX {
+            continue;
+        }
+        component[root] = c;
+        let mut stack = vec![root];
+        while let Some(s) = stack.pop() {
+            for prev in &reverse[s] {
+                if component[*prev] == usize::MAX {
+                    component[*prev] = c;
+                    stack.push(*prev);
+                }
+            }
+        }
+        c += 1;
+    }
+    component
+}
+
+/// Number of distinct routes from `f1` to any field in `ends`, saturating at `u128::MAX`.
+///
+/// Routes run over the states of `graph::KeyStates`, so keys are picked up and spent on doors as in
+/// `has_path`, but a route may take a transition again once its keys have changed. A route ends at
+/// the first exit it reaches and never comes back to a field with the same keys it had there before.
+/// Picking up a key or going through doors can't be undone, which splits the routes into stretches
+/// that are counted once for every way into them, no matter how many routes lead there.
+///
+/// Keys in hand have no limit and doors close again behind every route, whatever `maze::Rules` say.
+/// Traps only make routes slower, so they don't change the count. Returns an error if more than
+/// 64 keys are reachable from `f1`, or a bridge.
+pub fn count_paths(f1: Field, ends: Vec<Field>) -> Result<u128, String> {
+    let graph = Graph::new(f1);
+    let mut end_fields = vec![false; graph.len()];
+    ends.iter().filter_map(|f| graph.index_of(f)).for_each(|i| end_fields[i] = true);
+    let states = KeyStates::new(&graph)?;
+    let ends: Vec<bool> = (0..states.len()).map(|s| end_fields[states.field(s)]).collect();
+
+    let mut reverse: Vec<Vec<usize>> = vec![Vec::new(); states.len()];
+    for s in 0..states.len() {
+        states.moves(s).iter().for_each(|(_, to)| reverse[*to].push(s));
+    }
+    let mut useful = ends.clone();
+    let mut queue: VecDeque<usize> = (0..states.len()).filter(|s| ends[*s]).collect();
+    while let Some(s) = queue.pop_front() {
+        for prev in &reverse[s] {
+            if !useful[*prev] {
+                useful[*prev] = true;
+                queue.push_back(*prev);
+            }
+        }
+    }
+
+    let component = components(&states, &reverse);
+    let entered = vec![None; states.len()];
+    let mut counter = Counter { states, ends, useful, component, entered };
+    // Components are numbered so that moves only lead to later ones, so counting from the last one
+    // back has the routes from every state a component leads into ready when they are needed.
+    let mut entries: Vec<usize> = (0..counter.states.len())
+        .filter(|s| *s == 0 || reverse[*s].iter().any(|prev| counter.component[*prev] != counter.component[*s]))
+        .collect();
+    entries.sort_by_key(|s| std::cmp::Reverse(counter.component[*s]));
+    for s in entries {
+        counter.entered[s] = Some(if counter.useful[s] { counter.count(s) } else { 0 });
+    }
+    Ok(counter.entered[0].unwrap_or(0))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixtures::open_grid, reader::read_maze};
+
+    use super::count_paths;
+
+    #[test]
+    fn open_grids() {
+        // Simple routes between opposite corners of fully open grids.
+        for (n, routes) in [(3, 12), (6, 1262816)] {
+            let maze = read_maze(&open_grid(n, n, (n - 1, n - 1), &[])).unwrap();
+            assert_eq!(count_paths(maze.start(), maze.ends()).unwrap(), routes);
+        }
+    }
+
+    #[test]
+    fn long_corridor() {
+        // A single corridor winding through a 200x200 maze, open both ways: every row turns down into
+        // the next one at its east end if it is even and at its west end if it is odd.
+        let n: u8 = 200;
+        let turn = |y: u8| if y.is_multiple_of(2) { n - 1 } else { 0 };
+        let mut content = format!("SQUARE {} {}\n", n, n);
+        for y in 0..n {
+            for x in 0..n {
+                let open = [x > 0, x < n - 1, y > 0 && x == turn(y - 1), y < n - 1 && x == turn(y)].map(|o| if o { '1' } else { '0' });
+                let end = if (x, y) == (0, n - 1) { "0011" } else { "0000" };
+                content.push_str(&format!("{} 0000 {}\n", open.iter().collect::<String>(), end));
+            }
+        }
+        let maze = read_maze(&content).unwrap();
+        assert_eq!(count_paths(maze.start(), maze.ends()).unwrap(), 1);
+    }
+
+    #[test]
+    fn doors_need_keys() {
+        // (0,0) k --- (1,0) -|- (2,0) end, and a second door straight down from (1,0) to the exit (1,1).
+        let maze = read_maze("SQUARE 3 2\n0100 0000 1100\n1101 0101 0000\n1000 0000 0011\n0000 0000 0000\n1000 1000 0011\n0000 0000 0000\n").unwrap();
+        assert_eq!(count_paths(maze.start(), maze.ends()).unwrap(), 2);
+
+        // Without the key nothing gets through, but after picking it up (0,0) may be walked through again.
+        let maze = read_maze("SQUARE 3 1\n0100 0000 0000\n1100 0100 1100\n1000 0000 0011\n").unwrap();
+        assert_eq!(count_paths(maze.start(), maze.ends()).unwrap(), 1);
+        let maze = read_maze("SQUARE 3 1\n0100 0100 0000\n1100 1000 1100\n1000 1000 0011\n").unwrap();
+        assert_eq!(count_paths(maze.start(), maze.ends()).unwrap(), 0);
+    }
+}
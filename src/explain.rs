@@ -0,0 +1,141 @@
+use core::fmt;
+use std::{collections::HashSet, sync::Arc};
+use crate::{maze::{min_path_rules, min_path_trace, safe_neighbours, safe_position, Direction, Field, Maze, Mode, Rules}, reachability::reachability, trace::Event};
+
+/// Why no exit of a maze can be reached from its start.
+pub struct Explanation {
+    /// Number of exits in the maze.
+    pub exits: usize,
+    /// Fewest doors on any route to an exit, or `None` if the exits are walled off.
+    pub doors_needed: Option<u16>,
+    /// Keys lying on the fields that can actually be reached.
+    pub keys_collectable: u16,
+    /// Doors leading from a reachable field to one that can't be reached.
+    pub blocking: Vec<(Field, Direction)>,
+    /// Fields that can be reached with the keys that can be picked up on the way.
+    pub frontier: Vec<Field>,
+    /// Most keys in hand the rules allow, if carrying any number of them would get to an exit.
+    pub capacity: Option<u16>,
+}
+
+// "1 key", "2 keys" and so on.
+fn count(n: impl Into<usize>, what: &str) -> String {
+    let n = n.into();
+    format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+}
+
+fn list(fields: &[Field]) -> String {
+    let names: Vec<String> = fields.iter().map(|f| f.lock().unwrap().to_string()).collect();
+    names.join(" ")
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.doors_needed {
+            _ if self.exits == 0 => writeln!(f, "The maze has no exits.")?,
+            None if self.exits == 1 => writeln!(f, "The exit is walled off: no route leads from the start to it.")?,
+            None => writeln!(f, "All {} exits are walled off: no route leads from the start to any of them.", self.exits)?,
+            Some(n) if n > self.keys_collectable => writeln!(f, "The nearest exit is behind {}, but only {} can be collected.", count(n, "door"), count(self.keys_collectable, "key"))?,
+            Some(n) => writeln!(f, "The nearest exit is behind {} and {} can be collected, but {} used up before the last door.",
+                count(n, "door"), count(self.keys_collectable, "key"), if self.keys_collectable == 1 { "it is" } else { "they are" })?,
+        }
+        if let Some(n) = self.capacity {
+            writeln!(f, "Carrying more than {} at once would get to an exit.", count(n, "key"))?;
+        }
+        if !self.blocking.is_empty() {
+            let doors: Vec<String> = self.blocking.iter().map(|(field, d)| format!("{} {:?}", field.lock().unwrap(), d)).collect();
+            writeln!(f, "Blocking doors: {}", doors.join(", "))?;
+        }
+        writeln!(f, "Reachable fields: {}", list(&self.frontier))
+    }
+}
+
+/// Explains why `min_path_rules` finds no way out of the maze under `rules`, or returns `None` if
+/// an exit can be reached.
+///
+/// Keys, doors and hazards work as in the search itself, which goes through every way it can take
+/// before giving up, so the explanation grows exponentially with the number of keys and is meant to
+/// be asked for rather than given after every failed search.
+pub fn explain(maze: &Maze, rules: Rules) -> Option<Explanation> {
+    // A single search for all exits at once steps on every field any of the searches could.
+    let rules = Rules { bidirectional: false, per_exit: false, ..rules };
+    let mut events = Vec::new();
+    if min_path_trace(maze.start(), maze.ends(), rules, &mut events).is_some() {
+        return None;
+    }
+    let mut reached = HashSet::from([safe_position(maze.start())]);
+    let mut collected = HashSet::new();
+    for event in events {
+        match event {
+            Event::ENTER(p) => reached.insert(p),
+            Event::KEY(p) => collected.insert(p),
+            _ => false,
+        };
+    }
+    let frontier: Vec<Field> = maze.fields().iter().filter(|f| reached.contains(&safe_position(Arc::clone(f)))).cloned().collect();
+
+    let mut blocking = Vec::new();
+    for f in &frontier {
+        for (d, doors, f2) in safe_neighbours(Arc::clone(f)) {
+            if doors && !reached.contains(&safe_position(f2)) {
+                blocking.push((Arc::clone(f), d));
+            }
+        }
+    }
+    // Only a limit on the keys in hand can stand between the exits and a search that reaches them without it.
+    let unlimited = Rules { capacity: None, ..rules };
+    let capacity = rules.capacity.filter(|_| min_path_rules(maze.start(), maze.ends(), Mode::SERIAL, unlimited).is_some());
+    let r = reachability(maze);
+    let ends = maze.ends();
+    Some(Explanation {
+        exits: ends.len(),
+        doors_needed: ends.iter().filter_map(|f| r.keys_needed(f)).min(),
+        keys_collectable: collected.len() as u16,
+        blocking,
+        frontier,
+        capacity,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{maze::Rules, reader::read_maze};
+
+    use super::explain;
+
+    #[test]
+    fn not_enough_keys() {
+        // (0,0) k --- (1,0) -|- (2,0) -|- (3,0) end
+        let maze = read_maze("SQUARE 4 1\n0100 0000 1100\n1100 0100 0000\n1100 0100 0000\n1000 0000 0011\n").unwrap();
+        let e = explain(&maze, Rules::default()).unwrap();
+        assert_eq!(e.doors_needed, Some(2));
+        assert_eq!(e.keys_collectable, 1);
+        assert_eq!(e.frontier.len(), 3);
+        assert_eq!(e.blocking.len(), 1);
+        assert_eq!(e.to_string(), "The nearest exit is behind 2 doors, but only 1 key can be collected.\nBlocking doors: (2,0) EAST\nReachable fields: (0,0) (1,0) (2,0)\n");
+    }
+
+    #[test]
+    fn walled_off_or_solvable() {
+        let maze = read_maze("SQUARE 2 1\n0000 0000 0000\n0000 0000 0011\n").unwrap();
+        let e = explain(&maze, Rules::default()).unwrap();
+        assert_eq!(e.doors_needed, None);
+        assert!(e.to_string().starts_with("The exit is walled off"));
+
+        let maze = read_maze("SQUARE 2 1\n0100 0000 0000\n0000 0000 0011\n").unwrap();
+        assert!(explain(&maze, Rules::default()).is_none());
+    }
+
+    #[test]
+    fn rules() {
+        // (0,0) k --- (1,0) k -|-> (2,0) -|- (3,0) end, with no way back from (2,0).
+        let maze = read_maze("SQUARE 4 1\n0100 0000 1100\n1100 0100 1100\n0100 0100 0000\n1000 0000 0011\n").unwrap();
+        assert!(explain(&maze, Rules::default()).is_none());
+        // With one key in hand the second one is left lying, and can't be fetched after the first door.
+        let e = explain(&maze, Rules { capacity: Some(1), ..Rules::default() }).unwrap();
+        assert_eq!(e.keys_collectable, 1);
+        assert_eq!(e.capacity, Some(1));
+        assert_eq!(e.to_string(), "The nearest exit is behind 2 doors, but only 1 key can be collected.\n\
+            Carrying more than 1 key at once would get to an exit.\nBlocking doors: (2,0) EAST\nReachable fields: (0,0) (1,0) (2,0)\n");
+    }
+}
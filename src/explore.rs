@@ -0,0 +1,245 @@
+use core::fmt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::{maze::{Direction, Maze, Path, Position, Rules, DIRECTIONS}, play::Game, topology::Grid};
+
+/// How an agent that can only see the field it stands on looks for a way out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Keeps its right hand on the wall. Floors and portals are out of its reach.
+    RIGHT,
+    /// Keeps its left hand on the wall.
+    LEFT,
+    /// Heads north, or north-east on hex grids, following the wall with its right hand round whatever
+    /// is in the way until it faces north again having turned as much left as right.
+    PLEDGE,
+    /// Marks the ways it takes at both ends and never takes one a third time.
+    TREMAUX,
+    /// Remembers everything it has seen and walks to the nearest way it hasn't tried yet.
+    FRONTIER,
+}
+
+/// The walk an agent made through a maze, next to the shortest way out it didn't know about.
+pub struct Exploration {
+    /// Every move made, in order, ending on an exit if one was found.
+    pub walk: Path,
+    pub escaped: bool,
+    /// Number of different fields stepped on, the start included.
+    pub visited: usize,
+    /// Cost of the shortest way out, or `None` if there is none.
+    pub optimal: Option<usize>,
+}
+
+impl fmt::Display for Exploration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.escaped {
+            writeln!(f, "Out in {} steps.", self.walk.cost())?;
+        } else {
+            writeln!(f, "No way out found in {} steps.", self.walk.cost())?;
+        }
+        writeln!(f, "Fields visited: {}", self.visited)?;
+        match self.optimal {
+            Some(best) => writeln!(f, "Shortest way out: {}", best),
+            None => writeln!(f, "Shortest way out: none"),
+        }
+    }
+}
+
+/// Directions on a floor, clockwise.
+pub(crate) fn clockwise(grid: Grid) -> &'static [Direction] {
+    match grid {
+        Grid::SQUARE => &[Direction::NORTH, Direction::EAST, Direction::SOUTH, Direction::WEST],
+        Grid::HEX => &[Direction::NORTHEAST, Direction::EAST, Direction::SOUTHEAST, Direction::SOUTHWEST, Direction::WEST, Direction::NORTHWEST],
+    }
+}
+
+/// Lets an agent with the `strategy` walk from the start of the maze until it gets out or gives up.
+/// Keys and doors work as in `play::Game`. Agents give up when they run out of ways to try, or after
+/// four moves for every way out of every field.
+pub fn explore(maze: &Maze, strategy: Strategy, rules: Rules) -> Exploration {
+    let (game, visited) = walk(maze, strategy, rules);
+    Exploration { walk: game.path(), escaped: game.is_won(), visited, optimal: game.optimal() }
+}
+
+// Lets the agent walk, returning the game where it stopped and the number of fields it stepped on.
+pub(crate) fn walk(maze: &Maze, strategy: Strategy, rules: Rules) -> (Game<'_>, usize) {
+    let mut game = Game::new(maze, rules);
+    let mut visited = HashSet::from([game.position()]);
+    let limit = maze.fields().len() * DIRECTIONS.len() * 4;
+    match strategy {
+        Strategy::RIGHT => wall_follower(&mut game, maze.grid(), &mut visited, limit, true),
+        Strategy::LEFT => wall_follower(&mut game, maze.grid(), &mut visited, limit, false),
+        Strategy::PLEDGE => pledge(&mut game, maze.grid(), &mut visited, limit),
+        Strategy::TREMAUX => tremaux(&mut game, &mut visited, limit),
+        Strategy::FRONTIER => frontier(&mut game, &mut visited, limit),
+    }
+    (game, visited.len())
+}
+
+// Directions to try, as indices into `ring`, from the sharpest turn towards the hand's side round to turning back.
+fn turns(n: usize, heading: usize, right: bool) -> impl Iterator<Item = usize> {
+    let back = (heading + n / 2) % n;
+    (1..=n).map(move |k| if right { (back + n - k) % n } else { (back + k) % n })
+}
+
+fn wall_follower(game: &mut Game, grid: Grid, visited: &mut HashSet<Position>, limit: usize, right: bool) {
+    let ring = clockwise(grid);
+    let mut heading = 0;
+    while !game.is_won() && game.moves() < limit {
+        let Some(i) = turns(ring.len(), heading, right).find(|i| game.step(ring[*i]).is_ok()) else {
+            return;
+        };
+        heading = i;
+        visited.insert(game.position());
+    }
+}
+
+fn pledge(game: &mut Game, grid: Grid, visited: &mut HashSet<Position>, limit: usize) {
+    let ring = clockwise(grid);
+    let n = ring.len();
+    let mut heading = 0;
+    // Turns made while following the wall, clockwise ones counting up, in steps round the ring.
+    let mut turned: isize = 0;
+    let mut following = false;
+    while !game.is_won() && game.moves() < limit {
+        let next = if following {
+            turns(n, heading, true).enumerate().find(|(_, i)| game.step(ring[*i]).is_ok())
+                .map(|(k, i)| (i, (n / 2) as isize - 1 - k as isize))
+        } else if game.step(ring[heading]).is_ok() {
+            Some((heading, 0))
+        } else {
+            // Turn left until the way is free, leaving the wall on the right.
+            following = true;
+            (1..n).map(|k| (heading + n - k) % n).enumerate().find(|(_, i)| game.step(ring[*i]).is_ok())
+                .map(|(k, i)| (i, -1 - k as isize))
+        };
+        let Some((i, turn)) = next else {
+            return;
+        };
+        heading = i;
+        turned += turn;
+        if turned == 0 {
+            following = false;
+        }
+        visited.insert(game.position());
+    }
+}
+
+fn tremaux(game: &mut Game, visited: &mut HashSet<Position>, limit: usize) {
+    // Times every way was taken, counted at the field it leaves and at the field it leads to.
+    let mut marks: HashMap<(Position, Direction), u8> = HashMap::new();
+    let mut came: Option<(Direction, bool)> = None;
+    while !game.is_won() && game.moves() < limit {
+        let position = game.position();
+        let ways: Vec<Direction> = game.ways().into_iter().map(|(d, _)| d).collect();
+        let mark = |d: &Direction| marks.get(&(position, *d)).copied().unwrap_or(0);
+        let back = came.map(|(d, _)| d.get_opposite()).filter(|b| ways.contains(b));
+        // Coming along a new way to a field seen before means going back the same way.
+        let turn_back = came.is_some_and(|(_, fresh)| fresh).then_some(back).flatten().filter(|b| mark(b) < 2);
+        let next = turn_back.or_else(|| ways.iter().copied().filter(|d| mark(d) < 2).min_by_key(|d| (mark(d), Some(*d) == back)));
+        let Some(d) = next else {
+            return;
+        };
+        let from = mark(&d);
+        if game.step(d).is_err() {
+            // Locked doors are as good as a wall.
+            marks.insert((position, d), 2);
+            came = None;
+            continue;
+        }
+        marks.insert((position, d), from + 1);
+        let to = game.position();
+        let seen = !visited.insert(to);
+        if game.ways().iter().any(|(w, _)| *w == d.get_opposite()) {
+            *marks.entry((to, d.get_opposite())).or_insert(0) += 1;
+        }
+        came = Some((d, seen && from == 0));
+    }
+}
+
+fn frontier(game: &mut Game, visited: &mut HashSet<Position>, limit: usize) {
+    // Ways seen from every field visited, and where the ones taken so far lead.
+    let mut seen: HashMap<Position, Vec<(Direction, bool)>> = HashMap::new();
+    let mut leads: HashMap<(Position, Direction), Position> = HashMap::new();
+    while !game.is_won() && game.moves() < limit {
+        let position = game.position();
+        seen.entry(position).or_insert_with(|| game.ways());
+        // Doors are worth trying only with a key in hand.
+        let open = |doors: bool| !doors || game.keys() > 0;
+        // Breadth-first search over the ways taken before for the nearest field with a way not taken yet.
+        let mut via: HashMap<Position, Option<(Position, Direction)>> = HashMap::from([(position, None)]);
+        let mut queue = VecDeque::from([position]);
+        let mut target: Option<(Position, Direction)> = None;
+        while let Some(p) = queue.pop_front() {
+            let untried = seen[&p].iter().find(|(d, doors)| !leads.contains_key(&(p, *d)) && open(*doors));
+            if let Some((d, _)) = untried {
+                target = Some((p, *d));
+                break;
+            }
+            for (d, doors) in &seen[&p] {
+                let Some(q) = leads.get(&(p, *d)) else {
+                    continue;
+                };
+                if open(*doors) && !via.contains_key(q) {
+                    via.insert(*q, Some((p, *d)));
+                    queue.push_back(*q);
+                }
+            }
+        }
+        let Some((p, d)) = target else {
+            return;
+        };
+        let mut route = vec![d];
+        let mut curr = p;
+        while let Some(Some((prev, d))) = via.get(&curr) {
+            route.push(*d);
+            curr = *prev;
+        }
+        for d in route.into_iter().rev() {
+            let from = game.position();
+            if game.step(d).is_err() {
+                break;
+            }
+            let to = game.position();
+            leads.insert((from, d), to);
+            visited.insert(to);
+            seen.entry(to).or_insert_with(|| game.ways());
+            if game.is_won() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixtures::{follow, open_grid}, maze::Rules, reader::read_maze};
+
+    use super::{explore, Strategy};
+
+    #[test]
+    fn find_the_way_out() {
+        let maze = read_maze(include_str!("../examples/official.txt")).unwrap();
+        for strategy in [Strategy::RIGHT, Strategy::LEFT, Strategy::PLEDGE, Strategy::TREMAUX, Strategy::FRONTIER] {
+            let e = explore(&maze, strategy, Rules::default());
+            assert!(e.escaped, "{:?} didn't get out", strategy);
+            assert!(e.walk.cost() >= e.optimal.unwrap());
+            let end = follow(&maze.start(), &e.walk.steps());
+            assert!(maze.get(end).unwrap().lock().unwrap().is_end());
+        }
+    }
+
+    #[test]
+    fn exit_away_from_the_walls() {
+        // With no walls to keep to, the wall follower goes round in circles.
+        let maze = read_maze(&open_grid(5, 5, (2, 2), &[])).unwrap();
+        let e = explore(&maze, Strategy::RIGHT, Rules::default());
+        assert!(!e.escaped);
+        assert_eq!(e.visited, 4);
+        for strategy in [Strategy::TREMAUX, Strategy::FRONTIER] {
+            let e = explore(&maze, strategy, Rules::default());
+            assert!(e.escaped, "{:?} didn't get out", strategy);
+            assert_eq!(follow(&maze.start(), &e.walk.steps()), (2, 2, 0));
+        }
+        assert_eq!(explore(&maze, Strategy::FRONTIER, Rules::default()).to_string().lines().last(), Some("Shortest way out: 4"));
+    }
+}
@@ -0,0 +1,32 @@
+// Mazes and helpers shared by the tests of several modules.
+
+use std::sync::Arc;
+
+use crate::maze::{Direction, Field, Position};
+
+/// A fully open square grid with the exit on `end`, and doors on the east side of every field in
+/// `doors` that has a field east of it.
+pub fn open_grid(width: u8, height: u8, end: (u8, u8), doors: &[(u8, u8)]) -> String {
+    let mut content = format!("SQUARE {} {}\n", width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let open = [x > 0, x + 1 < width, y > 0, y + 1 < height].map(|o| if o { '1' } else { '0' });
+            let door = if doors.contains(&(x, y)) && x + 1 < width { "0100" } else { "0000" };
+            let flags = if (x, y) == end { "0011" } else { "0000" };
+            content.push_str(&format!("{} {} {}\n", open.iter().collect::<String>(), door, flags));
+        }
+    }
+    content
+}
+
+/// Where following `steps` from `from` leads.
+pub fn follow(from: &Field, steps: &[Direction]) -> Position {
+    let mut f = Arc::clone(from);
+    for d in steps {
+        let t = f.lock().unwrap().get_transition(*d).unwrap();
+        let next = t.lock().unwrap().get_field2();
+        f = next;
+    }
+    let p = f.lock().unwrap().position();
+    p
+}
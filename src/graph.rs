@@ -0,0 +1,215 @@
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
+use crate::maze::{refuse_hazards, safe_neighbours, safe_position, Direction, Field, Hazard, Position};
+
+/// A transition between two indexed fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub direction: Direction,
+    pub doors: bool,
+    pub to: usize,
+}
+
+/// Snapshot of the fields reachable from a start field, ignoring doors, with every field numbered
+/// so that searches over keys and doors can keep their state in plain vectors and bitmasks.
+/// The start field is always number 0.
+pub struct Graph {
+    fields: Vec<Field>,
+    positions: Vec<Position>,
+    index: HashMap<Position, usize>,
+    // Keys are numbered separately, so they can be tracked as bits of a mask.
+    keys: Vec<Option<u32>>,
+    key_count: u32,
+    ends: Vec<bool>,
+    hazards: Vec<Option<Hazard>>,
+    edges: Vec<Vec<Edge>>,
+}
+
+impl Graph {
+    pub fn new(start: Field) -> Self {
+        let mut g = Graph {
+            fields: Vec::new(),
+            positions: Vec::new(),
+            index: HashMap::new(),
+            keys: Vec::new(),
+            key_count: 0,
+            ends: Vec::new(),
+            hazards: Vec::new(),
+            edges: Vec::new(),
+        };
+        g.add(start);
+        let mut queue = VecDeque::from([0]);
+        while let Some(i) = queue.pop_front() {
+            let mut edges = Vec::new();
+            for (direction, doors, f2) in safe_neighbours(Arc::clone(&g.fields[i])) {
+                let to = match g.index.get(&safe_position(Arc::clone(&f2))) {
+                    Some(to) => *to,
+                    None => {
+                        let to = g.add(f2);
+                        queue.push_back(to);
+                        to
+                    },
+                };
+                edges.push(Edge { direction, doors, to });
+            }
+            g.edges[i] = edges;
+        }
+        g
+    }
+
+    fn add(&mut self, f: Field) -> usize {
+        let lf = f.lock().unwrap();
+        let (position, key, end, hazard) = (lf.position(), lf.has_key(), lf.is_end(), lf.get_hazard());
+        drop(lf);
+        let i = self.fields.len();
+        self.fields.push(f);
+        self.positions.push(position);
+        self.index.insert(position, i);
+        if key {
+            self.keys.push(Some(self.key_count));
+            self.key_count += 1;
+        } else {
+            self.keys.push(None);
+        }
+        self.ends.push(end);
+        self.hazards.push(hazard);
+        self.edges.push(Vec::new());
+        i
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn field(&self, i: usize) -> Field {
+        Arc::clone(&self.fields[i])
+    }
+
+    pub fn position(&self, i: usize) -> Position {
+        self.positions[i]
+    }
+
+    /// Returns an error naming the first field with a hazard for which `refused` holds, for a
+    /// `solver` that can't keep to it, e.g. "A tour".
+    pub fn refuse_hazards(&self, solver: &str, refused: impl Fn(Hazard) -> bool) -> Result<(), String> {
+        refuse_hazards(&self.fields, solver, refused)
+    }
+
+    /// Number of the field, or `None` if it isn't reachable from the start.
+    pub fn index_of(&self, f: &Field) -> Option<usize> {
+        self.index.get(&safe_position(Arc::clone(f))).copied()
+    }
+
+    pub fn has_key(&self, i: usize) -> bool {
+        self.keys[i].is_some()
+    }
+
+    /// Number of the key lying on field `i`, counting only fields with keys.
+    pub fn key_bit(&self, i: usize) -> Option<u32> {
+        self.keys[i]
+    }
+
+    pub fn key_count(&self) -> u32 {
+        self.key_count
+    }
+
+    pub fn is_end(&self, i: usize) -> bool {
+        self.ends[i]
+    }
+
+    /// Keys in hand a snare on field `i` takes, 0 if there is none.
+    pub fn snare(&self, i: usize) -> u16 {
+        match self.hazards[i] {
+            Some(Hazard::SNARE(n)) => n,
+            _ => 0,
+        }
+    }
+
+    pub fn edges(&self, i: usize) -> &[Edge] {
+        &self.edges[i]
+    }
+}
+
+// Field, keys picked up and keys in hand.
+type KeyState = (usize, u64, u16);
+
+/// Every combination of field, keys picked up and keys still in hand that can be reached from the
+/// start of a `Graph`, with the moves between them. State 0 is the start.
+///
+/// Keys are picked up and spent on doors as in `has_path`, but a walk through the states may come
+/// back to a field, and through a transition it took before, once its keys have changed, which
+/// `has_path` never does. Snares take keys in hand as they do there, but as the states don't tell
+/// which bridges were crossed, graphs with bridges are refused.
+pub struct KeyStates {
+    states: Vec<KeyState>,
+    moves: Vec<Vec<(Direction, usize)>>,
+}
+
+impl KeyStates {
+    /// Returns an error if more than 64 keys are reachable, as the keys picked up are tracked in a
+    /// bitmask, or if a bridge is.
+    pub fn new(graph: &Graph) -> Result<Self, String> {
+        if graph.key_count() > 64 {
+            return Err(format!("At most 64 keys can be tracked, {} are reachable.", graph.key_count()));
+        }
+        graph.refuse_hazards("A walk that may come back to a field", |h| h == Hazard::BRIDGE)?;
+        let arrive = |i: usize, keys: u64, hand: u16| {
+            let hand = hand.saturating_sub(graph.snare(i));
+            match graph.key_bit(i) {
+                Some(bit) if keys & (1 << bit) == 0 => (i, keys | 1 << bit, hand + 1),
+                _ => (i, keys, hand),
+            }
+        };
+        let start = arrive(0, 0, 0);
+        let mut ks = KeyStates {
+            states: vec![start],
+            moves: vec![Vec::new()],
+        };
+        let mut index = HashMap::from([(start, 0)]);
+        let mut s = 0;
+        while s < ks.states.len() {
+            let (i, keys, hand) = ks.states[s];
+            let mut moves = Vec::new();
+            for e in graph.edges(i) {
+                if e.doors && hand == 0 {
+                    continue;
+                }
+                let next = arrive(e.to, keys, if e.doors { hand - 1 } else { hand });
+                let to = *index.entry(next).or_insert_with(|| {
+                    ks.states.push(next);
+                    ks.moves.push(Vec::new());
+                    ks.states.len() - 1
+                });
+                moves.push((e.direction, to));
+            }
+            ks.moves[s] = moves;
+            s += 1;
+        }
+        Ok(ks)
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Number of the field the state is on, as numbered by the `Graph`.
+    pub fn field(&self, s: usize) -> usize {
+        self.states[s].0
+    }
+
+    /// Mask of the keys picked up so far, as numbered by `Graph::key_bit`.
+    pub fn keys(&self, s: usize) -> u64 {
+        self.states[s].1
+    }
+
+    pub fn moves(&self, s: usize) -> &[(Direction, usize)] {
+        &self.moves[s]
+    }
+}
@@ -0,0 +1,308 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, VecDeque}, sync::Arc};
+use crate::{maze::{refuse_hazards, safe_neighbours, safe_position, Direction, Field, Hazard, Maze, Path, Position}, topology::Grid};
+
+// An edge of the abstract graph, with the steps it stands for in the maze.
+struct Edge {
+    to: usize,
+    steps: Vec<Direction>,
+}
+
+/// Hierarchical abstraction of a maze for finding paths without doors in very large mazes.
+///
+/// Every floor is cut into square clusters. Fields on a transition leading into another cluster are
+/// entrances, and the shortest ways between the entrances of a cluster are computed up front, so a
+/// query only searches the clusters of its two fields and the graph of entrances. Transitions with
+/// doors are never taken, neither inside clusters nor across their borders: keys are ignored and
+/// doors count as walls, so a field reached only through doors has no path here even where the
+/// key-aware search finds one. Every step costs the same, so traps aren't kept to and a maze with
+/// any of them is refused.
+pub struct Hierarchy {
+    grid: Grid,
+    size: (u8, u8, u8),
+    cluster_size: u8,
+    positions: Vec<Position>,
+    moves: Vec<Vec<(Direction, usize)>>,
+    // Moves leading into every field, to search backwards from a target.
+    reverse: Vec<Vec<(Direction, usize)>>,
+    // Abstract nodes: the field every one of them stands for, and the node of every entrance field.
+    entrances: Vec<usize>,
+    node: HashMap<usize, usize>,
+    cluster_entrances: HashMap<Position, Vec<usize>>,
+    edges: Vec<Vec<Edge>>,
+    // Portals and transitions to fields that aren't neighbours can jump across the maze, so distances
+    // on the grid are only a lower bound without them.
+    admissible: bool,
+}
+
+// Fields reached from `from` without leaving its cluster, with the move that led to each of them.
+type Tree = HashMap<usize, Option<(usize, Direction)>>;
+
+impl Hierarchy {
+    /// Returns an error if the maze has traps.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cluster_size` is 0.
+    pub fn new(maze: &Maze, cluster_size: u8) -> Result<Self, String> {
+        assert!(cluster_size > 0, "clusters need at least one field");
+        let fields = maze.fields();
+        refuse_hazards(fields, "The clustered search", |h| matches!(h, Hazard::TRAP(_)))?;
+        let positions: Vec<Position> = fields.iter().map(|f| safe_position(Arc::clone(f))).collect();
+        let index: HashMap<Position, usize> = positions.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+        let mut moves: Vec<Vec<(Direction, usize)>> = vec![Vec::new(); fields.len()];
+        let mut reverse: Vec<Vec<(Direction, usize)>> = vec![Vec::new(); fields.len()];
+        let topology = maze.grid().topology();
+        let mut admissible = true;
+        for (i, f) in fields.iter().enumerate() {
+            for (d, doors, f2) in safe_neighbours(Arc::clone(f)) {
+                let Some(to) = index.get(&safe_position(f2)) else {
+                    continue;
+                };
+                admissible &= topology.is_step(positions[i], d, positions[*to]);
+                if !doors {
+                    moves[i].push((d, *to));
+                    reverse[*to].push((d, i));
+                }
+            }
+        }
+        let mut h = Hierarchy {
+            grid: maze.grid(),
+            size: maze.size(),
+            cluster_size,
+            positions,
+            moves,
+            reverse,
+            entrances: Vec::new(),
+            node: HashMap::new(),
+            cluster_entrances: HashMap::new(),
+            edges: Vec::new(),
+            admissible,
+        };
+
+        for i in 0..h.moves.len() {
+            for (d, to) in h.moves[i].clone() {
+                if h.cluster(i) == h.cluster(to) {
+                    continue;
+                }
+                let (a, b) = (h.add_entrance(i), h.add_entrance(to));
+                h.edges[a].push(Edge { to: b, steps: vec![d] });
+            }
+        }
+        for a in 0..h.entrances.len() {
+            let from = h.entrances[a];
+            let tree = h.local(from, false);
+            for b in h.cluster_entrances[&h.cluster(from)].clone() {
+                if b != a && tree.contains_key(&h.entrances[b]) {
+                    let steps = Hierarchy::steps(&tree, h.entrances[b]);
+                    h.edges[a].push(Edge { to: b, steps });
+                }
+            }
+        }
+        Ok(h)
+    }
+
+    fn cluster(&self, i: usize) -> Position {
+        let (x, y, z) = self.positions[i];
+        (x / self.cluster_size, y / self.cluster_size, z)
+    }
+
+    fn add_entrance(&mut self, i: usize) -> usize {
+        if let Some(n) = self.node.get(&i) {
+            return *n;
+        }
+        let n = self.entrances.len();
+        self.entrances.push(i);
+        self.node.insert(i, n);
+        self.cluster_entrances.entry(self.cluster(i)).or_default().push(n);
+        self.edges.push(Vec::new());
+        n
+    }
+
+    // Breadth-first search from `from` inside its cluster, following the transitions backwards if `backwards` is set.
+    fn local(&self, from: usize, backwards: bool) -> Tree {
+        let cluster = self.cluster(from);
+        let mut tree: Tree = HashMap::from([(from, None)]);
+        let mut queue = VecDeque::from([from]);
+        while let Some(u) = queue.pop_front() {
+            let moves = if backwards { &self.reverse[u] } else { &self.moves[u] };
+            for (d, v) in moves {
+                if self.cluster(*v) != cluster || tree.contains_key(v) {
+                    continue;
+                }
+                tree.insert(*v, Some((u, *d)));
+                queue.push_back(*v);
+            }
+        }
+        tree
+    }
+
+    // Steps from the root of a forward tree to `to`, in order.
+    fn steps(tree: &Tree, to: usize) -> Vec<Direction> {
+        let mut steps = Vec::new();
+        let mut curr = to;
+        while let Some(Some((prev, d))) = tree.get(&curr) {
+            steps.push(*d);
+            curr = *prev;
+        }
+        steps.reverse();
+        steps
+    }
+
+    fn index(&self, p: Position) -> Option<usize> {
+        let (width, height, floors) = self.size;
+        if p.0 >= width || p.1 >= height || p.2 >= floors {
+            return None;
+        }
+        Some((usize::from(p.2) * usize::from(height) + usize::from(p.1)) * usize::from(width) + usize::from(p.0))
+    }
+
+    /// Number of abstract nodes, the entrances between clusters.
+    pub fn entrances(&self) -> usize {
+        self.entrances.len()
+    }
+
+    /// Shortest path from `f1` to `f2` without going through doors, or `None` if there is none.
+    pub fn path(&self, f1: &Field, f2: &Field) -> Option<Path> {
+        let s = self.index(safe_position(Arc::clone(f1)))?;
+        let g = self.index(safe_position(Arc::clone(f2)))?;
+        let (start, goal) = (self.entrances.len(), self.entrances.len() + 1);
+        // The start and the goal join the abstract graph for this query only, through their clusters.
+        let from_start = self.local(s, false);
+        let mut start_edges: Vec<Edge> = self.cluster_entrances.get(&self.cluster(s)).into_iter().flatten()
+            .filter(|n| from_start.contains_key(&self.entrances[**n]))
+            .map(|n| Edge { to: *n, steps: Hierarchy::steps(&from_start, self.entrances[*n]) })
+            .collect();
+        if from_start.contains_key(&g) {
+            start_edges.push(Edge { to: goal, steps: Hierarchy::steps(&from_start, g) });
+        }
+        let to_goal = self.local(g, true);
+        let mut goal_edges: HashMap<usize, Vec<Direction>> = HashMap::new();
+        for n in self.cluster_entrances.get(&self.cluster(g)).into_iter().flatten() {
+            let mut curr = self.entrances[*n];
+            if !to_goal.contains_key(&curr) {
+                continue;
+            }
+            let mut steps = Vec::new();
+            while let Some(Some((next, d))) = to_goal.get(&curr) {
+                steps.push(*d);
+                curr = *next;
+            }
+            goal_edges.insert(*n, steps);
+        }
+
+        let target = self.positions[g];
+        let topology = self.grid.topology();
+        let estimate = |n: usize| if self.admissible && n < start { usize::from(topology.distance(self.positions[self.entrances[n]], target)) } else { 0 };
+        let mut distance: HashMap<usize, usize> = HashMap::from([(start, 0)]);
+        let mut via: HashMap<usize, (usize, &[Direction])> = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((estimate(start), start))]);
+        while let Some(Reverse((f, u))) = queue.pop() {
+            if f > distance[&u] + estimate(u) {
+                continue;
+            }
+            if u == goal {
+                let mut parts = Vec::new();
+                let mut curr = goal;
+                while let Some((prev, steps)) = via.get(&curr) {
+                    parts.push(*steps);
+                    curr = *prev;
+                }
+                let steps: Vec<Direction> = parts.into_iter().rev().flatten().copied().collect();
+                return Some(Path::from_steps(steps));
+            }
+            let du = distance[&u];
+            let edges: Vec<(usize, &[Direction])> = if u == start {
+                start_edges.iter().map(|e| (e.to, e.steps.as_slice())).collect()
+            } else {
+                let mut edges: Vec<(usize, &[Direction])> = self.edges[u].iter().map(|e| (e.to, e.steps.as_slice())).collect();
+                if let Some(steps) = goal_edges.get(&u) {
+                    edges.push((goal, steps.as_slice()));
+                }
+                edges
+            };
+            for (v, steps) in edges {
+                let dv = du + steps.len();
+                if distance.get(&v).is_some_and(|best| *best <= dv) {
+                    continue;
+                }
+                distance.insert(v, dv);
+                via.insert(v, (u, steps));
+                queue.push(Reverse((dv + estimate(v), v)));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixtures::{follow, open_grid}, maze::{Direction, Maze}, oracle::Oracle, reader::read_maze};
+
+    use super::Hierarchy;
+
+    // A square grid with doors all along the east side of column `doors`, and pseudo-random walls if `walls` is set.
+    fn maze(width: usize, height: usize, doors: usize, walls: bool) -> Maze {
+        let mut seed: u32 = 7;
+        let mut open = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            !walls || !(seed >> 16).is_multiple_of(4)
+        };
+        let mut east = vec![vec![false; width]; height];
+        let mut south = vec![vec![false; width]; height];
+        for y in 0..height {
+            for x in 0..width {
+                east[y][x] = x + 1 < width && open();
+                south[y][x] = y + 1 < height && open();
+            }
+        }
+        let bits = |b: [bool; 4]| b.map(|o| if o { '1' } else { '0' }).iter().collect::<String>();
+        let mut content = format!("SQUARE {} {}\n", width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let sides = [x > 0 && east[y][x - 1], east[y][x], y > 0 && south[y - 1][x], south[y][x]];
+                let door = [x > 0 && x - 1 == doors, x == doors, false, false];
+                content.push_str(&format!("{} {} 0000\n", bits(sides), bits(door)));
+            }
+        }
+        read_maze(&content).unwrap()
+    }
+
+    #[test]
+    fn matches_flat_search() {
+        let maze = maze(24, 18, 30, true);
+        let oracle = Oracle::new(&maze).unwrap();
+        let h = Hierarchy::new(&maze, 5).unwrap();
+        assert!(h.entrances() > 0);
+        let fields = maze.fields();
+        for i in (0..fields.len()).step_by(7) {
+            for j in (0..fields.len()).step_by(11) {
+                let p = h.path(&fields[i], &fields[j]);
+                assert_eq!(p.as_ref().map(|p| p.cost()), oracle.distance(&fields[i], &fields[j]));
+                if let Some(p) = p {
+                    assert_eq!(follow(&fields[i], &p.steps()), fields[j].lock().unwrap().position());
+                }
+            }
+        }
+
+        // Transitions leading far away from where a step would land, like portals.
+        let mut jumps = read_maze(&open_grid(10, 10, (9, 0), &[])).unwrap();
+        jumps.add_transition((0, 0, 0), Direction::SOUTH, (9, 9, 0), false);
+        jumps.add_transition((9, 9, 0), Direction::NORTH, (9, 0, 0), false);
+        let (from, to) = (jumps.get((0, 0, 0)).unwrap(), jumps.get((9, 0, 0)).unwrap());
+        assert_eq!(Oracle::new(&jumps).unwrap().distance(&from, &to), Some(2));
+        let p = Hierarchy::new(&jumps, 5).unwrap().path(&from, &to).unwrap();
+        assert_eq!(p.cost(), 2);
+        assert_eq!(follow(&from, &p.steps()), (9, 0, 0));
+    }
+
+    #[test]
+    fn doors_on_the_border() {
+        // Doors all along the border between the clusters of the first five columns and the rest.
+        let maze = maze(10, 4, 4, false);
+        let h = Hierarchy::new(&maze, 5).unwrap();
+        let (left, right) = (maze.get((0, 0, 0)).unwrap(), maze.get((9, 3, 0)).unwrap());
+        assert!(h.path(&left, &right).is_none());
+        assert_eq!(h.path(&left, &maze.get((4, 3, 0)).unwrap()).unwrap().cost(), 7);
+        assert_eq!(h.path(&maze.get((5, 0, 0)).unwrap(), &right).unwrap().cost(), 7);
+    }
+}
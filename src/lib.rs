@@ -1 +1,21 @@
-pub mod maze;
\ No newline at end of file
+pub mod agents;
+pub mod bidirectional;
+pub mod classic;
+pub mod count;
+pub mod explain;
+pub mod explore;
+#[cfg(test)]
+mod fixtures;
+pub mod graph;
+pub mod hpa;
+pub mod maze;
+pub mod oracle;
+pub mod paths;
+pub mod play;
+pub mod reachability;
+pub mod reader;
+pub mod replan;
+pub mod timed;
+pub mod topology;
+pub mod tour;
+pub mod trace;
\ No newline at end of file
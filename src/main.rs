@@ -1,167 +1,148 @@
 // Autor: Bojan Poprzen, E2-4-2022.
-use std::{env, fs, process, sync::{Mutex, Arc}, time::Instant};
-use lavirint::maze;
+use std::{env, fs, io::{self, Read, Write}, process, thread, time::{Duration, Instant}};
+use lavirint::{agents, classic, count, explain, explore, hpa, maze, paths, play, reachability, reader, timed, tour, trace};
 
-const USAGE: &str = "maze <file_path> <mode>\n\nInputs:\n\tfile_path: Path to a file that contains the maze.\n\tmode: accepted values are 's' or 'p', serial or parallel mode.";
-const MAZE_X: u8 = 9;
-const MAZE_Y: u8 = 6;
-
-fn read_fields(content: &String) -> (Vec<maze::Field>, Vec<maze::Field>) {
-    let mut fields: Vec<maze::Field> = Vec::new();
-    let mut ends: Vec<maze::Field> = Vec::new();
-    let (mut x, mut y, mut key, mut end) = (0, 0, false, false);
-    content.chars().enumerate().for_each(|(i, c)| {
-        // println!("{}:{}", i, c);
-        if i == 0 { // TODO remove?
-            return;
-        }
-        if i % 15 == 14 {
-            let f = maze::Field::new(Mutex::new(maze::SimpleField::new(x, y, key, end)));
-            // println!("{}, {}: {}, {}", i, f, f.has_key(), f.is_end());
-            fields.push(Arc::clone(&f));
-            if end {
-                ends.push(Arc::clone(&f));
-            }
-            if x == MAZE_X - 1 {
-                x = 0;
-                y = y + 1;
-            } else {
-                x = x + 1;
-            }
-        } else if i % 15 == 10 {
-            key = c == '1';
-        } else if i % 15 == 11 {
-            key = key && c == '1';
-        } else if i % 15 == 12 {
-            end = c == '1';
-        } else if i % 15 == 13 {
-            end = end && c == '1';
-        }
-    });
-    (fields, ends)
-}
-
-fn get_index(x: u8, y: u8) -> usize {
-    return (y * MAZE_X + x).into();
-}
-
-fn get_move(x: u8, y: u8, direction: maze::Direction) -> Option<usize> {
-    match direction {
-        maze::Direction::WEST => {
-            if x == 0 {
-                None
-            } else {
-                Some(get_index(x - 1, y))
-            }
-        },
-        maze::Direction::EAST => {
-            if x == MAZE_X - 1{
-                None
-            } else {
-                Some(get_index(x + 1, y))
-            }
-        },
-        maze::Direction::NORTH => {
-            if y == 0 {
-                None
-            } else {
-                Some(get_index(x, y - 1))
-            }
-        },
-        maze::Direction::SOUTH => {
-            if y == MAZE_Y - 1 {
-                None
-            } else {
-                Some(get_index(x, y + 1))
-            }
-        },
-    }
-}
-
-fn tie_fields(content: &String, fields: &mut Vec<maze::Field>) {
-    let (mut x, mut y, mut w, mut e, mut n, mut s, mut wd, mut ed, mut nd, mut sd) = (0, 0, false, false, false, false, false, false, false, false);
-    content.chars().enumerate().for_each(|(i, c)| {
-        // println!("{}:{}", i, c);
-        if i % 15 == 14 {
-            // println!("({}, {}): {}, {}, {}, {}", x, y, w, e, n, s);
-            let f1 = get_index(x, y);
-            let rf1 = fields.get(f1).unwrap();
-            if w {
-                let f2 = get_move(x, y, maze::Direction::WEST);
-                if f2.is_some() {
-                    let rf2 = fields.get(f2.unwrap());
-                    if rf2.is_some() {
-                        // println!("Tying WEST: {} -> {} {}", rf1.lock().unwrap(), rf2.unwrap().lock().unwrap(), wd);
-                        maze::Transition::new(wd, &maze::Direction::WEST, Arc::clone(rf1), Arc::clone(rf2.unwrap()));
-                    }
-                }
-            }
-            if e {
-                let f2 = get_move(x, y, maze::Direction::EAST);
-                if f2.is_some() {
-                    let rf2 = fields.get(f2.unwrap());
-                    if rf2.is_some() {
-                        // println!("Tying EAST: {} -> {} {}", rf1.lock().unwrap(), rf2.unwrap().lock().unwrap(), ed);
-                        maze::Transition::new(ed, &maze::Direction::EAST, Arc::clone(rf1), Arc::clone(rf2.unwrap()));
-                    }
-                }
-            }
-            if n {
-                let f2 = get_move(x, y, maze::Direction::NORTH);
-                if f2.is_some() {
-                    let rf2 = fields.get(f2.unwrap());
-                    if rf2.is_some() {
-                        // println!("Tying NORTH: {} -> {} {}", rf1.lock().unwrap(), rf2.unwrap().lock().unwrap(), nd);
-                        maze::Transition::new(nd, &maze::Direction::NORTH, Arc::clone(rf1), Arc::clone(rf2.unwrap()));
-                    }
-                }
-            }
-            if s {
-                let f2 = get_move(x, y, maze::Direction::SOUTH);
-                if f2.is_some() {
-                    let rf2 = fields.get(f2.unwrap());
-                    if rf2.is_some() {
-                        // println!("Tying SOUTH: {} -> {} {}", rf1.lock().unwrap(), rf2.unwrap().lock().unwrap(), sd);
-                        maze::Transition::new(sd, &maze::Direction::SOUTH, Arc::clone(rf1), Arc::clone(rf2.unwrap()));
-                    }
-                }
-            }
-
-            if x == MAZE_X - 1 {
-                x = 0;
-                y = y + 1;
-            } else {
-                x = x + 1;
-            }
-        } else if i % 15 == 0 {
-            w = c == '1';
-        } else if i % 15 == 1 {
-            e = c == '1';
-        } else if i % 15 == 2 {
-            n = c == '1';
-        } else if i % 15 == 3 {
-            s = c == '1';
-        } else if i % 15 == 5 {
-            wd = c == '1';
-        } else if i % 15 == 6 {
-            ed = c == '1';
-        } else if i % 15 == 7 {
-            nd = c == '1';
-        } else if i % 15 == 8 {
-            sd = c == '1';
-        }
-    });
-}
+const USAGE: &str = "maze <file_path> <mode> [options]\n\nInputs:\n\tfile_path: Path to a file that contains the maze.\n\tmode: accepted values are 's' or 'p', serial or parallel mode (parallel only with --per-exit), or 'play' to walk through the maze yourself\n\t\twith the arrow keys, y u b n for the diagonals of hex grids, < and > for stairs, p for portals and q to quit.\n\nOptions:\n\t--open-doors: unlocked doors stay open for the rest of the path.\n\t--bidirectional: search from the start and the exits at once while there are no doors in the way.\n\t--per-exit: search for every exit on its own instead of for all of them at once, in threads of their own in mode 'p'.\n\t--capacity <n>: carry at most n keys at once, leaving the others lying until one is spent.\n\t--tour: pick up every key before leaving through an exit.\n\t--timed: keep to the schedules of the transitions, waiting for them to open where it pays,\n\t\tand count the ticks traps hold the path for.\n\t--explain: tell why there is no path to an exit when none is found.\n\t--reachability: show how many keys it takes to reach every field ('#' if none will do).\n\t--all-paths: list every shortest path to any exit.\n\t--paths <k>: list the k shortest paths to any exit.\n\t--count: count the routes to an exit, which may come back to a field once the keys have changed.\n\t--classic <algorithm>: solve the maze by following the 'right' or 'left' wall, the 'pledge' rule,\n\t\t'tremaux' marks or dead-end 'filling', or 'all' of them side by side.\n\t--agents <x,y[,z]:...>: move agents from these fields to exits at once, never two on a field at the same time.\n\t--shared-keys: every key can be picked up by one of the agents only.\n\t--clusters <size>: find the shortest path through clusters of size x size fields, ignoring keys\n\t\tand taking doors for walls.\n\t--explore <agent>: walk through the maze seeing only the current field, with the 'right' or 'left' hand on the wall,\n\t\tby the 'pledge' rule, with 'tremaux' marks or towards the nearest 'frontier', and compare the walk with the shortest path.\n\t--stats: show how much work the search took (not with --tour, --timed or --trace).\n\t--trace <file>: record every step of the search to a file (always in serial mode, not with --bidirectional).\n\t--replay <file>: animate a recorded search in the terminal.\n\t--delay <ms>: time between the frames of the animation, 100 by default.";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
-        println!("{}", USAGE);
+    if args.len() < 3 {
+        println!("{}\n\n{}", USAGE, reader::FORMAT);
         return 
     }
     let file_path = &args[1];
     let mode = &args[2];
+    let mut rules = maze::Rules::default();
+    let (mut key_tour, mut report, mut all_paths, mut count_routes, mut show_stats) = (false, false, false, false, false);
+    let (mut timed_doors, mut explain_failure) = (false, false);
+    let mut k_paths: Option<usize> = None;
+    let mut clusters: Option<u8> = None;
+    let mut strategy: Option<explore::Strategy> = None;
+    let mut algorithms: Option<Vec<classic::Algorithm>> = None;
+    let mut agent_starts: Option<Vec<maze::Position>> = None;
+    let mut sharing = agents::Sharing::OWN;
+    let (mut trace_file, mut replay_file): (Option<String>, Option<String>) = (None, None);
+    let mut delay = Duration::from_millis(100);
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--open-doors" => rules.doors_stay_open = true,
+            "--bidirectional" => rules.bidirectional = true,
+            "--per-exit" => rules.per_exit = true,
+            "--tour" => key_tour = true,
+            "--timed" => timed_doors = true,
+            "--explain" => explain_failure = true,
+            "--reachability" => report = true,
+            "--all-paths" => all_paths = true,
+            "--count" => count_routes = true,
+            "--stats" => show_stats = true,
+            "--capacity" if rules.capacity.is_none() => match options.next().and_then(|n| n.parse().ok()) {
+                Some(n) => rules.capacity = Some(n),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--paths" if k_paths.is_none() => match options.next().and_then(|k| k.parse().ok()) {
+                Some(k) => k_paths = Some(k),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--clusters" if clusters.is_none() => match options.next().and_then(|size| size.parse().ok()).filter(|size| *size > 0) {
+                Some(size) => clusters = Some(size),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--explore" if strategy.is_none() => match options.next().map(|s| s.as_str()) {
+                Some("right") => strategy = Some(explore::Strategy::RIGHT),
+                Some("left") => strategy = Some(explore::Strategy::LEFT),
+                Some("pledge") => strategy = Some(explore::Strategy::PLEDGE),
+                Some("tremaux") => strategy = Some(explore::Strategy::TREMAUX),
+                Some("frontier") => strategy = Some(explore::Strategy::FRONTIER),
+                _ => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--classic" if algorithms.is_none() => match options.next().map(|s| s.as_str()) {
+                Some("right") => algorithms = Some(vec![classic::Algorithm::RIGHT]),
+                Some("left") => algorithms = Some(vec![classic::Algorithm::LEFT]),
+                Some("pledge") => algorithms = Some(vec![classic::Algorithm::PLEDGE]),
+                Some("tremaux") => algorithms = Some(vec![classic::Algorithm::TREMAUX]),
+                Some("filling") => algorithms = Some(vec![classic::Algorithm::FILLING]),
+                Some("all") => algorithms = Some(classic::ALGORITHMS.to_vec()),
+                _ => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--agents" if agent_starts.is_none() => match options.next().and_then(|s| parse_positions(s)) {
+                Some(starts) => agent_starts = Some(starts),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--shared-keys" => sharing = agents::Sharing::SHARED,
+            "--trace" if trace_file.is_none() => match options.next() {
+                Some(f) => trace_file = Some(f.clone()),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--replay" if replay_file.is_none() => match options.next() {
+                Some(f) => replay_file = Some(f.clone()),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            "--delay" => match options.next().and_then(|ms| ms.parse().ok()) {
+                Some(ms) => delay = Duration::from_millis(ms),
+                None => {
+                    println!("{}\n\n{}", USAGE, reader::FORMAT);
+                    return
+                },
+            },
+            _ => {
+                println!("{}\n\n{}", USAGE, reader::FORMAT);
+                return
+            },
+        }
+    }
+
+    if trace_file.is_some() && rules.bidirectional {
+        println!("The search from both ends can't be traced, leave out --bidirectional or --trace.");
+        process::exit(1)
+    }
+    // Options that change how keys and doors work, which not every search keeps to.
+    let key_rules = rules.capacity.is_some() || rules.doors_stay_open;
+    if key_rules && report {
+        println!("The reachability report counts a key for every door, leave out --capacity and --open-doors or --reachability.");
+        process::exit(1)
+    }
+    if key_rules && (all_paths || k_paths.is_some()) {
+        println!("The shortest paths are listed without the key rules, leave out --capacity and --open-doors or --all-paths and --paths.");
+        process::exit(1)
+    }
+    if key_rules && count_routes {
+        println!("Routes are counted without the key rules, leave out --capacity and --open-doors or --count.");
+        process::exit(1)
+    }
+    if show_stats && (key_tour || timed_doors || trace_file.is_some()) {
+        println!("Only the search for the nearest exit counts its work, leave out --tour, --timed and --trace or --stats.");
+        process::exit(1)
+    }
+    if explain_failure && (key_tour || timed_doors) {
+        println!("Only a failed search for the nearest exit can be explained, leave out --tour and --timed or --explain.");
+        process::exit(1)
+    }
 
     let content: String;
     match fs::read_to_string(file_path) {
@@ -172,21 +153,215 @@ fn main() {
         },
     }
 
-    let (mut fields, ends) = read_fields(&content);
-    tie_fields(&content, &mut fields);
-    let mut p: Option<maze::Path> = None;
+    let maze = match reader::read_maze(&content) {
+        Ok(maze) => maze,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1)
+        },
+    };
+    rules.grid = maze.grid();
+    if mode == "play" {
+        play_game(&maze, rules);
+        return
+    }
+    if let Some(replay_file) = replay_file {
+        let events = match fs::read_to_string(&replay_file).map_err(|_| format!("Unable to read file: {}", replay_file)).and_then(|s| trace::read_trace(&s)) {
+            Ok(events) => events,
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1)
+            },
+        };
+        for frame in trace::Replay::new(&maze, &events) {
+            // Clear the screen and draw the next frame from the top.
+            print!("\x1b[2J\x1b[H{}", frame);
+            io::stdout().flush().unwrap();
+            thread::sleep(delay);
+        }
+        return
+    }
+    if report {
+        let r = reachability::reachability(&maze);
+        print!("{}", r);
+        let unreachable: Vec<String> = r.unreachable().iter().map(|f| f.lock().unwrap().to_string()).collect();
+        println!("\nUnreachable: {}", unreachable.join(" "));
+        return
+    }
+    if count_routes {
+        println!("{}", or_exit(count::count_paths(maze.start(), maze.ends())));
+        return
+    }
+    if all_paths || k_paths.is_some() {
+        let found: Vec<maze::Path> = match k_paths {
+            Some(k) => or_exit(paths::k_min_paths(maze.start(), maze.ends(), k)),
+            None => or_exit(paths::all_min_paths(maze.start(), maze.ends())).collect(),
+        };
+        found.iter().for_each(|p| println!("{}: {}", p.cost(), p));
+        if found.is_empty() {
+            println!("Path not found.");
+        }
+        return
+    }
+    if let Some(size) = clusters {
+        let h = or_exit(hpa::Hierarchy::new(&maze, size));
+        let start = Instant::now();
+        let p = maze.ends().iter().filter_map(|end| h.path(&maze.start(), end)).min_by_key(|p| p.cost());
+        let duration = start.elapsed();
+        match p {
+            Some(p) => p.print_path(),
+            None => println!("Path not found."),
+        }
+        println!("\nentrances: {}\nin: {:?}", h.entrances(), duration);
+        return
+    }
+    if let Some(starts) = agent_starts {
+        match or_exit(agents::plan(&maze, &starts, sharing, 10_000)) {
+            Some(paths) => starts.iter().zip(paths).for_each(|((x, y, z), p)| println!("({},{},{}) {}: {}", x, y, z, p.cost(), p)),
+            None => println!("Path not found."),
+        }
+        return
+    }
+    if let Some(algorithms) = algorithms {
+        for (i, algorithm) in algorithms.into_iter().enumerate() {
+            let (p, report) = classic::solve(&maze, algorithm);
+            let found = p.map_or(String::from("Path not found."), |p| format!("{}: {}", p.cost(), p));
+            print!("{}{:?}\n{}\n{}", if i > 0 { "\n" } else { "" }, algorithm, found, report);
+        }
+        return
+    }
+    if let Some(strategy) = strategy {
+        let e = explore::explore(&maze, strategy, rules);
+        e.walk.print_path();
+        print!("\n{}", e);
+        return
+    }
     let start = Instant::now();
-    if mode == "p" {
-        p = maze::min_path(Arc::clone(&fields[0]), ends, maze::Mode::PARALLEL);
+    let search_mode = if mode == "p" { maze::Mode::PARALLEL } else { maze::Mode::SERIAL };
+    let mut stats: Option<maze::Stats> = None;
+    let p = if key_tour {
+        or_exit(tour::min_key_tour(&maze, rules))
+    } else if timed_doors {
+        or_exit(timed::min_path_timed(&maze, rules))
+    } else if let Some(trace_file) = &trace_file {
+        let mut events = Vec::new();
+        let p = maze::min_path_trace(maze.start(), maze.ends(), rules, &mut events);
+        if fs::write(trace_file, trace::write_trace(&events)).is_err() {
+            println!("Unable to write file: {}", trace_file);
+            process::exit(1)
+        }
+        p
+    } else if show_stats {
+        let (p, s) = maze::min_path_stats(maze.start(), maze.ends(), search_mode, rules);
+        stats = Some(s);
+        p
     } else {
-        p = maze::min_path(Arc::clone(&fields[0]), ends, maze::Mode::SERIAL);
-    }
+        maze::min_path_rules(maze.start(), maze.ends(), search_mode, rules)
+    };
     // let p = maze::has_path(Arc::clone(&fields[0]), Arc::clone(&fields[47]));
     let duration = start.elapsed();
-    if p.is_some() {
-        p.unwrap().print_path();
+    if let Some(p) = p {
+        p.print_path();
     } else {
-        println!("Path not found.")
+        println!("Path not found.");
+        if explain_failure {
+            if let Some(e) = explain::explain(&maze, rules) {
+                print!("{}", e);
+            }
+        }
+    }
+    if let Some(s) = stats {
+        print!("\n{}", s);
     }
     println!("\nin: {:?}", duration);
 }
+
+// The value of a result, or the end of the program with its error.
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1)
+        },
+    }
+}
+
+// Switches the terminal to reading single key presses without echoing them, returning the settings
+// to restore afterwards, or `None` if there is no terminal.
+fn raw_terminal() -> Option<String> {
+    let saved = process::Command::new("stty").arg("-g").stdin(fs::File::open("/dev/tty").ok()?).output().ok()?;
+    let raw = process::Command::new("stty").args(["-icanon", "-echo", "min", "1"]).stdin(fs::File::open("/dev/tty").ok()?).status().ok()?;
+    (saved.status.success() && raw.success()).then(|| String::from_utf8_lossy(&saved.stdout).trim().to_string())
+}
+
+fn restore_terminal(saved: &str) {
+    if let Ok(tty) = fs::File::open("/dev/tty") {
+        let _ = process::Command::new("stty").arg(saved).stdin(tty).status();
+    }
+}
+
+// Reads a key press, `None` standing for quitting. Arrow keys come as the escape sequences ESC [ A to D.
+fn read_move(tty: &mut fs::File) -> Option<Option<maze::Direction>> {
+    let mut key = [0u8; 1];
+    tty.read_exact(&mut key).ok()?;
+    let direction = match key[0] {
+        b'q' => return None,
+        0x1b => {
+            let mut sequence = [0u8; 2];
+            tty.read_exact(&mut sequence).ok()?;
+            match sequence {
+                [b'[', b'A'] => Some(maze::Direction::NORTH),
+                [b'[', b'B'] => Some(maze::Direction::SOUTH),
+                [b'[', b'C'] => Some(maze::Direction::EAST),
+                [b'[', b'D'] => Some(maze::Direction::WEST),
+                _ => None,
+            }
+        },
+        b'y' => Some(maze::Direction::NORTHWEST),
+        b'u' => Some(maze::Direction::NORTHEAST),
+        b'b' => Some(maze::Direction::SOUTHWEST),
+        b'n' => Some(maze::Direction::SOUTHEAST),
+        b'<' => Some(maze::Direction::UP),
+        b'>' => Some(maze::Direction::DOWN),
+        b'p' => Some(maze::Direction::PORTAL),
+        _ => None,
+    };
+    Some(direction)
+}
+
+fn play_game(maze: &maze::Maze, rules: maze::Rules) {
+    let (Some(saved), Ok(mut tty)) = (raw_terminal(), fs::File::open("/dev/tty")) else {
+        println!("Playing needs a terminal.");
+        process::exit(1)
+    };
+    let mut game = play::Game::new(maze, rules);
+    let mut message = String::new();
+    loop {
+        // Clear the screen and draw the maze from the top.
+        println!("\x1b[2J\x1b[H{}{}", game.draw(), message);
+        io::stdout().flush().unwrap();
+        if game.is_won() {
+            break;
+        }
+        match read_move(&mut tty) {
+            None => break,
+            Some(None) => message = String::new(),
+            Some(Some(d)) => message = game.step(d).err().unwrap_or_default(),
+        }
+    }
+    restore_terminal(&saved);
+    println!("{}", game.summary());
+}
+
+// Positions written as x,y or x,y,z and separated by colons.
+fn parse_positions(s: &str) -> Option<Vec<maze::Position>> {
+    s.split(':').map(|p| {
+        let coords: Vec<u8> = p.split(',').map(|c| c.parse().ok()).collect::<Option<_>>()?;
+        match coords[..] {
+            [x, y] => Some((x, y, 0)),
+            [x, y, z] => Some((x, y, z)),
+            _ => None,
+        }
+    }).collect()
+}
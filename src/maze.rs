@@ -1,69 +1,110 @@
-use core::{fmt, time};
-use std::{option::Option, thread::{self, sleep, JoinHandle}, sync::{Arc, Mutex}, fmt::Debug, cmp::Ordering, collections::BinaryHeap};
+use core::fmt;
+use crate::{bidirectional::doorless_path, topology::{Grid, Topology}, trace::Event};
+use std::{option::Option, thread::{self, JoinHandle}, sync::{Arc, Mutex, Weak}, fmt::Debug, cmp::Ordering, collections::{BinaryHeap, HashMap, HashSet}, time::{Duration, Instant}};
 
-#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
 pub enum Direction {
     SOUTH,
     EAST,
     NORTH,
     WEST,
+    UP,
+    DOWN,
+    PORTAL,
+    NORTHEAST,
+    NORTHWEST,
+    SOUTHEAST,
+    SOUTHWEST,
+    /// Staying on the field for a tick in timed plans. No transition goes this way.
+    WAIT,
 }
 
+/// Every kind of transition a field can have, in the order solvers try them.
+pub const DIRECTIONS: [Direction; 11] = [Direction::SOUTH, Direction::EAST, Direction::WEST, Direction::NORTH, Direction::NORTHEAST, Direction::NORTHWEST, Direction::SOUTHEAST, Direction::SOUTHWEST, Direction::UP, Direction::DOWN, Direction::PORTAL];
+
 impl Direction {
-    fn get_opposite(&self) -> Direction {
+    pub fn get_opposite(&self) -> Direction {
         match self {
             Direction::WEST => Direction::EAST,
             Direction::EAST => Direction::WEST,
             Direction::NORTH => Direction::SOUTH,
             Direction::SOUTH => Direction::NORTH,
+            Direction::UP => Direction::DOWN,
+            Direction::DOWN => Direction::UP,
+            Direction::PORTAL => Direction::PORTAL,
+            Direction::NORTHEAST => Direction::SOUTHWEST,
+            Direction::SOUTHWEST => Direction::NORTHEAST,
+            Direction::NORTHWEST => Direction::SOUTHEAST,
+            Direction::SOUTHEAST => Direction::NORTHWEST,
+            Direction::WAIT => Direction::WAIT,
         }
     }
 }
 
+/// Coordinates of a field: column, row and floor.
+pub type Position = (u8, u8, u8);
+
+/// Something on a field that gets in the way of walking through it.
+///
+/// Every solver keeps off forbidden fields. A solver that can't keep to the other hazards returns an
+/// error for a maze that has them, unless they can't change what it finds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hazard {
+    /// Holds whoever steps on it for this many ticks, which paths show as `WAIT` steps after the
+    /// move onto it and count in their cost.
+    TRAP(u16),
+    /// Takes up to this many keys in hand from whoever steps on it, before the field's own key is picked up.
+    SNARE(u16),
+    /// Collapses once it has been crossed, so a path can step on it only once.
+    BRIDGE,
+    /// Can't be stepped on at all.
+    FORBIDDEN,
+}
+
 pub struct SimpleField {
     x: u8,
     y: u8,
-    w: OptionalTransition,
-    e: OptionalTransition,
-    n: OptionalTransition,
-    s: OptionalTransition,
+    z: u8,
+    transitions: [OptionalTransition; DIRECTIONS.len()],
     key: bool,
     end: bool,
+    hazard: Option<Hazard>,
+    // Transitions created to lead into the field. They belong to the fields they lead from, which
+    // may have replaced or removed them since, see `safe_incoming`.
+    incoming: Vec<Weak<Mutex<Transition>>>,
 }
 
 impl SimpleField {
     pub fn new(x: u8, y: u8, key: bool, end: bool) -> Self {
+        Self::new_3d(x, y, 0, key, end)
+    }
+
+    pub fn new_3d(x: u8, y: u8, z: u8, key: bool, end: bool) -> Self {
         SimpleField {
-            x: x,
-            y: y,
-            w: None,
-            e: None,
-            n: None,
-            s: None,
-            key: key,
-            end: end,
+            x,
+            y,
+            z,
+            transitions: Default::default(),
+            key,
+            end,
+            hazard: None,
+            incoming: Vec::new(),
         }
     }
 
     pub fn add_transition(&mut self, direction: &Direction, transition: Arc<Mutex<Transition>>) {
-        let f = Some(transition);
-        match direction {
-            Direction::WEST => self.w = f,
-            Direction::EAST => self.e = f,
-            Direction::NORTH => self.n = f,
-            Direction::SOUTH => self.s = f,
+        if let Some(t) = self.transitions.get_mut(*direction as usize) {
+            *t = Some(transition);
         }
     }
 
+    /// Takes away the transition in `direction`, returning it if there was one.
+    pub fn remove_transition(&mut self, direction: Direction) -> Option<Arc<Mutex<Transition>>> {
+        self.transitions.get_mut(direction as usize)?.take()
+    }
+
     pub fn get_transition(&self, direction: Direction) -> Option<Arc<Mutex<Transition>>> {
-        let t: &Option<Arc<Mutex<Transition>>>;
-        match direction {
-            Direction::WEST => t = &self.w,
-            Direction::EAST => t = &self.e,
-            Direction::NORTH => t = &self.n,
-            Direction::SOUTH => t = &self.s,
-        }
-        if let Some(real_t) = t {
+        if let Some(Some(real_t)) = self.transitions.get(direction as usize) {
             return Some(Arc::clone(real_t));
         }
         None
@@ -76,30 +117,77 @@ impl SimpleField {
     pub fn is_end(&self) -> bool {
         self.end
     }
+
+    pub fn position(&self) -> Position {
+        (self.x, self.y, self.z)
+    }
+
+    pub fn get_hazard(&self) -> Option<Hazard> {
+        self.hazard
+    }
+
+    pub fn set_hazard(&mut self, hazard: Option<Hazard>) {
+        self.hazard = hazard;
+    }
 }
 
 impl fmt::Display for SimpleField {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "({},{})", self.x, self.y)
+        if self.z == 0 {
+            write!(f, "({},{})", self.x, self.y)
+        } else {
+            write!(f, "({},{},{})", self.x, self.y, self.z)
+        }
     }
 }
 
 impl fmt::Debug for SimpleField {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "({},{})", self.x, self.y)
+        write!(f, "{}", self)
     }
 }
 
 impl PartialEq for SimpleField {
     fn eq(&self, other: &Self) -> bool {
-        self.x == other.x && self.y == other.y
+        self.position() == other.position()
     }
 }
 
 pub type Field = Arc<Mutex<SimpleField>>;
 
+/// When a transition can be passed: at the ticks in one of the `open` intervals, each from its
+/// first tick up to but not including its last. With a `period` the intervals repeat every `period`
+/// ticks, without one (`period` of 0) the transition stays shut once the last interval is over.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub period: usize,
+    pub open: Vec<(usize, usize)>,
+}
+
+impl Schedule {
+    /// Open on every other tick, starting with the first.
+    pub fn even() -> Self {
+        Schedule { period: 2, open: vec![(0, 1)] }
+    }
+
+    /// Whether a move through the transition can start at tick `t`.
+    pub fn is_open(&self, t: usize) -> bool {
+        let t = if self.period > 0 { t % self.period } else { t };
+        self.open.iter().any(|(from, to)| (*from..*to).contains(&t))
+    }
+}
+
+// What keeps a transition shut: nothing, doors that take a key, or doors that open on their own
+// when the schedule says so.
+#[derive(Clone, PartialEq)]
+enum Doors {
+    Free,
+    Locked,
+    Timed(Schedule),
+}
+
 pub struct Transition {
-    doors: bool,
+    doors: Doors,
     field1: Field,
     field2: Field,
 }
@@ -107,21 +195,48 @@ pub struct Transition {
 impl Transition {
     pub fn new(doors: bool, direction: &Direction, field1: Field, field2: Field) -> Arc<Mutex<Self>> {
         let t = Transition {
-            doors: doors,
+            doors: if doors { Doors::Locked } else { Doors::Free },
             field1: Arc::clone(&field1),
             field2: Arc::clone(&field2),
         };
         let rt = Arc::new(Mutex::new(t));
 
-        let mut f1 = field1.lock();
+        let f1 = field1.lock();
         // let mut f2 = field2.borrow_mut();
         f1.unwrap().add_transition(direction, Arc::clone(&rt));
         // f2.add_transition(&direction.get_opposite(), Arc::clone(&rt));
+        let mut f2 = field2.lock().unwrap();
+        f2.incoming.retain(|t| t.strong_count() > 0);
+        f2.incoming.push(Arc::downgrade(&rt));
         rt
     }
 
+    /// Whether there are doors on the transition that take a key to open.
     pub fn has_doors(&self) -> bool {
-        self.doors
+        self.doors == Doors::Locked
+    }
+
+    /// When the doors on the transition open on their own, or `None` if the time doesn't matter.
+    pub fn get_schedule(&self) -> Option<Schedule> {
+        match &self.doors {
+            Doors::Timed(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether a move through the transition can start at tick `t`, locked doors aside.
+    pub fn is_open_at(&self, t: usize) -> bool {
+        self.get_schedule().is_none_or(|s| s.is_open(t))
+    }
+
+    /// Puts doors that open on the `schedule` on this transition alone, in place of any doors that
+    /// take a key, or takes them away with `None`. See `Maze::set_schedule` for both ways at once.
+    pub fn set_schedule(&mut self, schedule: Option<Schedule>) {
+        match schedule {
+            Some(s) => self.doors = Doors::Timed(s),
+            None if self.get_schedule().is_some() => self.doors = Doors::Free,
+            None => {},
+        }
     }
 
     pub fn get_field1(&self) -> Field {
@@ -135,84 +250,357 @@ impl Transition {
 
 impl fmt::Display for Transition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut t = "-";
-        if self.doors {
-            t = "|";
-        }
+        let t = match self.doors {
+            Doors::Free => "-",
+            Doors::Locked => "|",
+            Doors::Timed(_) => "~",
+        };
         write!(f, "{} -{}-> {}", self.get_field1().lock().unwrap(), t, self.get_field2().lock().unwrap())
     }
 }
 
 impl fmt::Debug for Transition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut t = "-";
-        if self.doors {
-            t = "|";
-        }
-        write!(f, "{} -{}-> {}", self.get_field1().lock().unwrap(), t, self.get_field2().lock().unwrap())
+        write!(f, "{}", self)
     }
 }
 
 impl PartialEq for Transition {
     fn eq(&self, other: &Self) -> bool {
-        return self.get_field1().lock().unwrap().eq(&other.get_field1().lock().unwrap()) && self.get_field2().lock().unwrap().eq(&other.get_field2().lock().unwrap())
+        self.get_field1().lock().unwrap().eq(&other.get_field1().lock().unwrap()) && self.get_field2().lock().unwrap().eq(&other.get_field2().lock().unwrap())
             // || (self.get_field2() == other.get_field1() && self.get_field1() == other.get_field2())
     }
 }
 
 type OptionalTransition = Option<Arc<Mutex<Transition>>>;
 
+/// A whole maze: its fields stored floor by floor, row by row, and the grid they are laid out on.
+pub struct Maze {
+    grid: Grid,
+    width: u8,
+    height: u8,
+    floors: u8,
+    fields: Vec<Field>,
+    // Fields changed through the maze, in order, so that solvers can catch up with the changes.
+    changes: Vec<Position>,
+}
+
+impl Maze {
+    pub fn new(grid: Grid, width: u8, height: u8, fields: Vec<Field>) -> Self {
+        let floors = (fields.len() / (usize::from(width) * usize::from(height))) as u8;
+        Maze {
+            grid,
+            width,
+            height,
+            floors,
+            fields,
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, p: Position) -> Option<Field> {
+        if p.0 >= self.width || p.1 >= self.height || p.2 >= self.floors {
+            return None;
+        }
+        self.fields.get(self.index(p)).map(Arc::clone)
+    }
+
+    /// The field every search starts from, the top left corner of the first floor.
+    pub fn start(&self) -> Field {
+        Arc::clone(&self.fields[0])
+    }
+
+    pub fn ends(&self) -> Vec<Field> {
+        self.fields.iter().filter(|f| f.lock().unwrap().is_end()).map(Arc::clone).collect()
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub fn grid(&self) -> Grid {
+        self.grid
+    }
+
+    pub fn size(&self) -> (u8, u8, u8) {
+        (self.width, self.height, self.floors)
+    }
+
+    /// Number of changes made through the maze so far.
+    pub fn revision(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Fields changed since the maze was at `revision`, oldest first and possibly repeated.
+    pub fn changes_since(&self, revision: usize) -> &[Position] {
+        &self.changes[revision.min(self.changes.len())..]
+    }
+
+    /// Adds a transition from the field at `p` in `direction` to the field at `to`, replacing the one
+    /// that was there. Only this one way is added. Returns `false` if either position is outside the
+    /// maze or the direction is `WAIT`, which no transition goes.
+    pub fn add_transition(&mut self, p: Position, direction: Direction, to: Position, doors: bool) -> bool {
+        if direction == Direction::WAIT {
+            return false;
+        }
+        let (Some(f1), Some(f2)) = (self.get(p), self.get(to)) else {
+            return false;
+        };
+        Transition::new(doors, &direction, f1, f2);
+        self.changes.push(p);
+        true
+    }
+
+    // The transition leaving `p` in `direction` and the one leading back from where it goes, if there is such.
+    fn both_ways(&self, p: Position, direction: Direction) -> Option<(Arc<Mutex<Transition>>, OptionalTransition, Position)> {
+        let t = safe_get_transition(self.get(p)?, direction)?;
+        let f2 = t.lock().unwrap().get_field2();
+        let to = safe_position(Arc::clone(&f2));
+        let back = safe_get_transition(f2, direction.get_opposite())
+            .filter(|b| safe_position(b.lock().unwrap().get_field2()) == p);
+        Some((t, back, to))
+    }
+
+    /// Removes the transition leaving the field at `p` in `direction`, together with the one leading
+    /// back from the field it went to. Returns whether there was one.
+    pub fn remove_transition(&mut self, p: Position, direction: Direction) -> bool {
+        let Some((_, back, to)) = self.both_ways(p, direction) else {
+            return false;
+        };
+        safe_remove_transition(Arc::clone(&self.fields[self.index(p)]), direction);
+        self.changes.push(p);
+        if back.is_some() {
+            safe_remove_transition(Arc::clone(&self.fields[self.index(to)]), direction.get_opposite());
+            self.changes.push(to);
+        }
+        true
+    }
+
+    /// Puts up a wall between the field at `p` and its neighbour in `direction` on the same floor, or
+    /// takes it down, leaving an open way in both directions. Returns whether the maze changed.
+    pub fn set_wall(&mut self, p: Position, direction: Direction, wall: bool) -> bool {
+        let to = self.grid.topology().step(p.0, p.1, direction).map(|(x, y)| (x, y, p.2));
+        let Some(to) = to.filter(|to| self.get(*to).is_some() && self.get(p).is_some()) else {
+            return false;
+        };
+        if wall {
+            let mut changed = false;
+            for (from, d) in [(p, direction), (to, direction.get_opposite())] {
+                if safe_remove_transition(Arc::clone(&self.fields[self.index(from)]), d) {
+                    self.changes.push(from);
+                    changed = true;
+                }
+            }
+            return changed;
+        }
+        let mut changed = false;
+        for (from, d, target) in [(p, direction, to), (to, direction.get_opposite(), p)] {
+            let leads = self.get(from).and_then(|f| safe_get_transition(f, d))
+                .is_some_and(|t| safe_position(t.lock().unwrap().get_field2()) == target);
+            if !leads {
+                changed |= self.add_transition(from, d, target, false);
+            }
+        }
+        changed
+    }
+
+    /// Puts doors that take a key on the transition leaving the field at `p` in `direction` and the one
+    /// leading back, in place of any doors on a schedule, or takes them away. Returns whether the maze changed.
+    pub fn set_door(&mut self, p: Position, direction: Direction, doors: bool) -> bool {
+        let Some((t, back, to)) = self.both_ways(p, direction) else {
+            return false;
+        };
+        let mut changed = false;
+        for (from, t) in [(p, Some(t)), (to, back)] {
+            let Some(t) = t else {
+                continue;
+            };
+            let mut lt = t.lock().unwrap();
+            if lt.has_doors() != doors {
+                lt.doors = if doors { Doors::Locked } else { Doors::Free };
+                drop(lt);
+                self.changes.push(from);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Puts doors that open on the `schedule` on the transition leaving the field at `p` in `direction`
+    /// and the one leading back, in place of any doors that take a key, or takes them away with `None`.
+    /// Only `timed::min_path_timed` keeps to schedules, the other solvers pass such doors freely.
+    /// Returns whether the maze changed.
+    pub fn set_schedule(&mut self, p: Position, direction: Direction, schedule: Option<Schedule>) -> bool {
+        let Some((t, back, to)) = self.both_ways(p, direction) else {
+            return false;
+        };
+        let mut changed = false;
+        for (from, t) in [(p, Some(t)), (to, back)] {
+            let Some(t) = t else {
+                continue;
+            };
+            let mut lt = t.lock().unwrap();
+            if lt.get_schedule() != schedule {
+                lt.set_schedule(schedule.clone());
+                drop(lt);
+                self.changes.push(from);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Puts doors on the transition leaving the field at `p` in `direction`, or takes them away if it
+    /// had them, the same on the way back. Returns `false` if there is no such transition.
+    pub fn toggle_door(&mut self, p: Position, direction: Direction) -> bool {
+        let Some((t, _, _)) = self.both_ways(p, direction) else {
+            return false;
+        };
+        let doors = t.lock().unwrap().has_doors();
+        self.set_door(p, direction, !doors)
+    }
+
+    /// Puts a key on the field at `p` or takes it away, returning whether the maze changed.
+    pub fn set_key(&mut self, p: Position, key: bool) -> bool {
+        self.set_flag(p, |f| &mut f.key, key)
+    }
+
+    /// Makes the field at `p` an exit or stops it being one, returning whether the maze changed.
+    pub fn set_end(&mut self, p: Position, end: bool) -> bool {
+        self.set_flag(p, |f| &mut f.end, end)
+    }
+
+    /// Puts the `hazard` on the field at `p`, or clears it with `None`. Returns whether the maze changed.
+    pub fn set_hazard(&mut self, p: Position, hazard: Option<Hazard>) -> bool {
+        let Some(f) = self.get(p) else {
+            return false;
+        };
+        let mut lf = f.lock().unwrap();
+        if lf.hazard == hazard {
+            return false;
+        }
+        lf.hazard = hazard;
+        drop(lf);
+        self.changes.push(p);
+        true
+    }
+
+    fn set_flag(&mut self, p: Position, flag: fn(&mut SimpleField) -> &mut bool, value: bool) -> bool {
+        let Some(f) = self.get(p) else {
+            return false;
+        };
+        let mut lf = f.lock().unwrap();
+        if *flag(&mut lf) == value {
+            return false;
+        }
+        *flag(&mut lf) = value;
+        drop(lf);
+        self.changes.push(p);
+        true
+    }
+
+    fn index(&self, p: Position) -> usize {
+        (usize::from(p.2) * usize::from(self.height) + usize::from(p.1)) * usize::from(self.width) + usize::from(p.0)
+    }
+}
+
 pub struct Path {
     steps: Vec<Direction>,
 }
 
 impl Path {
+    // Steps are kept from the last to the first, the order in which the recursive search finds them.
+    pub(crate) fn from_steps(mut steps: Vec<Direction>) -> Self {
+        steps.reverse();
+        Path { steps }
+    }
     pub fn cost(&self) -> usize {
-        return self.steps.len();
+        self.steps.len()
+    }
+    pub fn steps(&self) -> Vec<Direction> {
+        self.steps.iter().rev().copied().collect()
     }
     pub fn print_path(&self) {
-        let n = self.steps.len();
-        self.steps.iter().rev().enumerate().for_each(|(i, x)| {
-            print!("{:?}", x);
-            if i != n - 1 {
-                print!(" -> ");
-            } else {
-                println!();
-            }
-        })
+        println!("{}", self);
     }
     fn add_step(&mut self, step: Direction) {
         self.steps.push(step);
     }
 }
 
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let steps: Vec<String> = self.steps.iter().rev().map(|d| format!("{:?}", d)).collect();
+        write!(f, "{}", steps.join(" -> "))
+    }
+}
+
+/// How the searches for the exits are run when `Rules::per_exit` asks for one per exit: each in a
+/// thread of its own or one after another. A single search for all the exits at once always runs
+/// on the calling thread, whatever the mode.
 #[derive(PartialEq)]
 pub enum Mode {
     PARALLEL,
     SERIAL
 }
 
+/// Options that change how the key-aware search behaves.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Rules {
+    /// Once a door is unlocked it stays open in both directions for the rest of the path.
+    pub doors_stay_open: bool,
+    /// Shape of the cells, used to order moves by their distance to the target.
+    pub grid: Grid,
+    /// Look for the path from both ends at once, falling back to the key-aware search if doors get in the way.
+    pub bidirectional: bool,
+    /// Search for every exit on its own, in threads or one after another as the `Mode` says,
+    /// instead of for all of them in one search.
+    pub per_exit: bool,
+    /// Most keys that can be in hand at once, `None` for no limit. A key that doesn't fit is left
+    /// lying for a later visit, once a key has been spent on doors.
+    pub capacity: Option<u16>,
+}
+
 pub fn min_path(f1: Field, ends: Vec<Field>, mode: Mode) -> Option<Path> {
-    let mut handles: Vec<thread::JoinHandle<Option<Path>>> = Vec::new();
+    min_path_rules(f1, ends, mode, Rules::default())
+}
+
+/// Shortest path from `f1` to the nearest of `ends` under `rules`. `mode` only matters with
+/// `rules.per_exit` set, see `Mode`.
+pub fn min_path_rules(f1: Field, ends: Vec<Field>, mode: Mode, rules: Rules) -> Option<Path> {
+    min_path_search(f1, ends, mode, rules, false).0
+}
+
+/// Like `min_path_rules`, but also reports how much work the search took.
+pub fn min_path_stats(f1: Field, ends: Vec<Field>, mode: Mode, rules: Rules) -> (Option<Path>, Stats) {
+    min_path_search(f1, ends, mode, rules, true)
+}
+
+fn min_path_search(f1: Field, ends: Vec<Field>, mode: Mode, rules: Rules, collect: bool) -> (Option<Path>, Stats) {
+    if !rules.per_exit {
+        return measured_search(f1, ends, rules, collect);
+    }
+    let mut handles: Vec<thread::JoinHandle<(Option<Path>, Stats)>> = Vec::new();
     for end in ends {
         let brf1 = Arc::clone(&f1);
         let brend = Arc::clone(&end);
-        let handle: JoinHandle<_>;
-        if mode == Mode::PARALLEL {
-            handle = thread::spawn(move || {
-                has_path(brf1, brend)
-            });
+        let handle: JoinHandle<_> = if mode == Mode::PARALLEL {
+            thread::spawn(move || {
+                measured_search(brf1, vec![brend], rules, collect)
+            })
         } else {
-            let result = has_path(brf1, brend);
-            handle = thread::spawn(|| {
+            let result = measured_search(brf1, vec![brend], rules, collect);
+            thread::spawn(|| {
                 result
-            });
-        }
+            })
+        };
         handles.push(handle);
     }
     let mut min: Option<Path> = None;
+    let mut stats = Stats::default();
     for handle in handles {
-        let got_min = handle.join().unwrap();
+        let (got_min, got_stats) = handle.join().unwrap();
+        stats.merge(got_stats);
         if got_min.is_none() {
             continue;
         }
@@ -229,20 +617,137 @@ pub fn min_path(f1: Field, ends: Vec<Field>, mode: Mode) -> Option<Path> {
             min = Some(curr_min)
         }
     }
-    return min
+    (min, stats)
+}
+
+// Searches for the nearest of `ends`, counting the work and measuring the time it took if `collect` is set.
+fn measured_search(f1: Field, ends: Vec<Field>, rules: Rules, collect: bool) -> (Option<Path>, Stats) {
+    let mut stats = Stats::default();
+    if !collect {
+        return (has_path_search(f1, &ends, &rules, &mut Probe::default()), stats);
+    }
+    let start = Instant::now();
+    let path = has_path_search(f1, &ends, &rules, &mut Probe { stats: Some(&mut stats), trace: None });
+    stats.timings.push((ends, start.elapsed()));
+    (path, stats)
 }
 
 pub fn has_path(f1: Field, f2: Field) -> Option<Path> {
-    let mut k = Keys::new();
-    if let Some((path, _)) = has_path_keys(f1, f2, &mut k, &mut Vec::new(), None) {
-        return Some(path)
+    has_path_rules(f1, f2, Rules::default())
+}
+
+pub fn has_path_rules(f1: Field, f2: Field, rules: Rules) -> Option<Path> {
+    has_path_search(f1, &[f2], &rules, &mut Probe::default())
+}
+
+/// Like `has_path_rules`, adding the work done by the search to `stats`.
+pub fn has_path_stats(f1: Field, f2: Field, rules: Rules, stats: &mut Stats) -> Option<Path> {
+    has_path_search(f1, &[f2], &rules, &mut Probe { stats: Some(stats), trace: None })
+}
+
+/// Like `has_path_rules`, appending every step the search takes to `trace`. Only the key-aware
+/// search records its steps: a path found from both ends with `rules.bidirectional` leaves no trace.
+pub fn has_path_trace(f1: Field, f2: Field, rules: Rules, trace: &mut Vec<Event>) -> Option<Path> {
+    has_path_search(f1, &[f2], &rules, &mut Probe { stats: None, trace: Some(trace) })
+}
+
+/// Like `min_path_rules` in serial mode, appending every step of the search to `trace`,
+/// or of the searches for all exits, one after another, if `rules.per_exit` is set.
+pub fn min_path_trace(f1: Field, ends: Vec<Field>, rules: Rules, trace: &mut Vec<Event>) -> Option<Path> {
+    if !rules.per_exit {
+        return has_path_search(f1, &ends, &rules, &mut Probe { stats: None, trace: Some(trace) });
+    }
+    let mut min: Option<Path> = None;
+    for end in ends {
+        if let Some(p) = has_path_trace(Arc::clone(&f1), end, rules, trace) {
+            if min.as_ref().is_none_or(|m| p.cost() < m.cost()) {
+                min = Some(p);
+            }
+        }
+    }
+    min
+}
+
+// Shortest path from `f1` to the nearest of `ends`.
+fn has_path_search(f1: Field, ends: &[Field], rules: &Rules, probe: &mut Probe) -> Option<Path> {
+    if ends.is_empty() {
+        return None;
+    }
+    if rules.bidirectional {
+        if let Some(path) = doorless_path(Arc::clone(&f1), ends) {
+            return path;
+        }
+    }
+    let mut k = Keys::with_capacity(rules.capacity);
+    has_path_keys(f1, ends, &mut k, rules, probe)
+}
+
+// Where the key-aware search reports what it is doing, if anyone asked.
+#[derive(Default)]
+struct Probe<'a> {
+    stats: Option<&'a mut Stats>,
+    trace: Option<&'a mut Vec<Event>>,
+}
+
+impl Probe<'_> {
+    fn record(&mut self, event: impl FnOnce() -> Event) {
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.push(event());
+        }
+    }
+}
+
+/// Counters collected by the key-aware search, see `min_path_stats`.
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    /// Fields the search stepped on and tried to move on from.
+    pub expanded: u64,
+    /// Branches abandoned because they couldn't beat the shortest path found so far, or came back
+    /// to a field on the path without having picked up a key or opened a door since.
+    pub pruned: u64,
+    /// Doors that couldn't be opened for lack of keys.
+    pub blocked: u64,
+    /// Most transitions on the path being explored at any time.
+    pub max_depth: usize,
+    /// Time every search took together with the exits it looked for, in the order they were searched.
+    pub timings: Vec<(Vec<Field>, Duration)>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.expanded += other.expanded;
+        self.pruned += other.pruned;
+        self.blocked += other.blocked;
+        self.max_depth = self.max_depth.max(other.max_depth);
+        self.timings.extend(other.timings);
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Fields expanded: {}", self.expanded)?;
+        writeln!(f, "Branches pruned: {}", self.pruned)?;
+        writeln!(f, "Doors blocked: {}", self.blocked)?;
+        writeln!(f, "Max depth: {}", self.max_depth)?;
+        for (ends, duration) in &self.timings {
+            let names: Vec<String> = ends.iter().map(|e| e.lock().unwrap().to_string()).collect();
+            writeln!(f, "Search for {}: {:?}", names.join(" "), duration)?;
+        }
+        Ok(())
     }
-    None
 }
 
 pub struct Keys {
     fields: Vec<Field>,
     total: u16,
+    opened: Vec<Arc<Mutex<Transition>>>,
+    capacity: Option<u16>,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Keys {
@@ -250,11 +755,19 @@ impl Keys {
         Keys {
             fields: Vec::new(),
             total: 0,
+            opened: Vec::new(),
+            capacity: None,
         }
     }
 
+    /// Keys of which at most `capacity` can be in hand at once, `None` for no limit.
+    pub fn with_capacity(capacity: Option<u16>) -> Self {
+        Keys { capacity, ..Keys::new() }
+    }
+
+    /// Picks up the key on `f` unless it was picked up already or there is no room for it in hand.
     pub fn add(&mut self, f: Field) -> bool {
-        if self.fields.iter().any(|e| safe_equals(Arc::clone(&e), Arc::clone(&f))) {
+        if self.capacity.is_some_and(|c| self.total >= c) || self.fields.iter().any(|e| safe_equals(Arc::clone(e), Arc::clone(&f))) {
             return false;
         }
         self.fields.push(f);
@@ -278,6 +791,24 @@ impl Keys {
         self.fields.pop();
         self.total -= 1;
     }
+
+    pub fn open(&mut self, t: Arc<Mutex<Transition>>) {
+        self.opened.push(t);
+    }
+
+    pub fn is_open(&self, t: Arc<Mutex<Transition>>) -> bool {
+        self.opened.iter().any(|e| safe_equals_t(Arc::clone(e), Arc::clone(&t)) || safe_reversed_t(Arc::clone(e), Arc::clone(&t)))
+    }
+
+    pub fn close(&mut self) {
+        self.opened.pop();
+    }
+}
+
+// Takes away the transition in `direction`, returning whether there was one.
+fn safe_remove_transition(f1: Field, direction: Direction) -> bool {
+    let removed = f1.lock().unwrap().remove_transition(direction).is_some();
+    removed
 }
 
 fn safe_get_transition(f1: Field, direction: Direction) -> Option<Arc<Mutex<Transition>>> {
@@ -289,16 +820,68 @@ fn safe_has_key(f1: Field) -> bool {
     f1.lock().unwrap().has_key()
 }
 
+pub(crate) fn safe_position(f1: Field) -> Position {
+    f1.lock().unwrap().position()
+}
+
+/// Transitions leaving a field: their direction, whether they have doors and the field they lead to.
+/// Transitions to forbidden fields are left out.
+pub(crate) fn safe_neighbours(f1: Field) -> Vec<(Direction, bool, Field)> {
+    let mut result = Vec::new();
+    for d in DIRECTIONS {
+        if let Some(t) = safe_get_transition(Arc::clone(&f1), d) {
+            let lt = t.lock().unwrap();
+            let f2 = lt.get_field2();
+            if safe_hazard(Arc::clone(&f2)) != Some(Hazard::FORBIDDEN) {
+                result.push((d, lt.has_doors(), f2));
+            }
+        }
+    }
+    result
+}
+
+// The other way round from `safe_neighbours`: every transition leading into `f1` from a field that
+// still has it, with its direction, whether there are doors on it and the field it leads from.
+pub(crate) fn safe_incoming(f1: Field) -> Vec<(Direction, bool, Field)> {
+    let incoming: Vec<Arc<Mutex<Transition>>> = f1.lock().unwrap().incoming.iter().filter_map(Weak::upgrade).collect();
+    let mut result = Vec::new();
+    for t in incoming {
+        let lt = t.lock().unwrap();
+        let (from, doors) = (lt.get_field1(), lt.has_doors());
+        drop(lt);
+        let direction = DIRECTIONS.into_iter().find(|d| safe_get_transition(Arc::clone(&from), *d).is_some_and(|other| Arc::ptr_eq(&other, &t)));
+        if let Some(d) = direction {
+            if safe_hazard(Arc::clone(&from)) != Some(Hazard::FORBIDDEN) {
+                result.push((d, doors, from));
+            }
+        }
+    }
+    result
+}
+
+// Returns an error naming the first of `fields` with a hazard `solver` can't keep to, those for
+// which `refused` holds.
+pub(crate) fn refuse_hazards<'a>(fields: impl IntoIterator<Item = &'a Field>, solver: &str, refused: impl Fn(Hazard) -> bool) -> Result<(), String> {
+    for f in fields {
+        let lf = f.lock().unwrap();
+        if let Some(hazard) = lf.get_hazard().filter(|h| refused(*h)) {
+            return Err(format!("{} can't keep to the {:?} on {}.", solver, hazard, lf));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn safe_hazard(f1: Field) -> Option<Hazard> {
+    f1.lock().unwrap().get_hazard()
+}
+
 fn safe_equals(f1: Field, f2: Field) -> bool {
-    let lf1 = f1.lock().unwrap();
-    let ff1 = (lf1.x, lf1.y);
-    drop(lf1);
-    let lf2 = f2.lock().unwrap();
-    let ff2 = (lf2.x, lf2.y);
-    drop(lf2);  
+    let ff1 = safe_position(f1);
+    let ff2 = safe_position(f2);
     ff1 == ff2
 }
 
+#[allow(dead_code)]
 fn safe_print(f: Field) -> String {
     format!("{}", f.lock().unwrap())
 }
@@ -315,16 +898,26 @@ fn safe_equals_t(t1: Arc<Mutex<Transition>>, t2: Arc<Mutex<Transition>>) -> bool
     let tt22 = lt2.get_field2();
     drop(lt2);
 
-    return safe_equals(Arc::clone(&tt1), Arc::clone(&tt11)) && safe_equals(Arc::clone(&tt2), Arc::clone(&tt22))
+    safe_equals(Arc::clone(&tt1), Arc::clone(&tt11)) && safe_equals(Arc::clone(&tt2), Arc::clone(&tt22))
 }
 
-fn diff(x1: u8, y1: u8, x2: u8, y2: u8) -> u8 {
-    return  x2.abs_diff(x1) + y2.abs_diff(y1)
+fn safe_reversed_t(t1: Arc<Mutex<Transition>>, t2: Arc<Mutex<Transition>>) -> bool {
+    let lt1 = t1.lock().unwrap();
+    let tt1 = lt1.get_field1();
+    let tt2 = lt1.get_field2();
+    drop(lt1);
+
+    let lt2 = t2.lock().unwrap();
+    let tt11 = lt2.get_field1();
+    let tt22 = lt2.get_field2();
+    drop(lt2);
+
+    safe_equals(Arc::clone(&tt1), Arc::clone(&tt22)) && safe_equals(Arc::clone(&tt2), Arc::clone(&tt11))
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 struct DirectionCost {
-    cost: u8,
+    cost: u16,
     direction: Direction,
 }
 
@@ -341,18 +934,12 @@ impl PartialOrd for DirectionCost {
     }
 }
 
-fn directions_heuristic(f1: Field, end: Field) -> Vec<Direction> {
+fn directions_heuristic(f1: Field, targets: &[Position], topology: &dyn Topology) -> Vec<Direction> {
     let mut result: Vec<_> = Vec::new();
-    let directions = [Direction::SOUTH, Direction::EAST, Direction::WEST, Direction::NORTH];
     let mut distances = BinaryHeap::new();
 
-    let (x1, y1): (u8, u8);
     // println!("Field: {}", safe_print(Arc::clone(&f1)));
-    let lf1 = end.lock().unwrap();
-    x1 = lf1.x;
-    y1 = lf1.y;
-    drop(lf1);
-    for d in directions {
+    for d in DIRECTIONS {
         let t_pos = safe_get_transition(Arc::clone(&f1), d);
         if t_pos.is_none() {
             continue;
@@ -361,105 +948,209 @@ fn directions_heuristic(f1: Field, end: Field) -> Vec<Direction> {
         let tf2 = t_poss.lock().unwrap();
         let f2 = tf2.get_field2();
         drop(tf2);
-        let lff2 = f2.lock().unwrap();
-        let (x2, y2) = (lff2.x, lff2.y);
-        drop(lff2);
-        let cost = diff(x1, y1, x2, y2);
+        let p2 = safe_position(f2);
+        let cost = targets.iter().map(|p1| topology.distance(*p1, p2)).min().unwrap_or(0);
         // println!("\tCost to {} is {}", safe_print(Arc::clone(&f2)), cost);
-        let dc = DirectionCost{cost: cost, direction: d};
+        let dc = DirectionCost{cost, direction: d};
         distances.push(dc);
     }
     while !distances.is_empty() {
         result.push(distances.pop().unwrap().direction);
     }
-    // println!("For ({}, {}) best: {:?}", x1, y1, result);
     result
 }
 
-fn has_path_keys(f1: Field, f2: Field, keys: &mut Keys, transitions: &mut Vec<Arc<Mutex<Transition>>>, mut min_transitions: Option<usize>) -> Option<(Path, usize)> {
-    // sleep(time::Duration::from_secs(2));
-    // println!("Comparing: {:} and {:}", safe_print(Arc::clone(&f1)), safe_print(Arc::clone(&f2)));
-    if safe_equals(Arc::clone(&f1), Arc::clone(&f2)) {
-        return Some((Path { steps: Vec::new() }, transitions.len()));
-    }
-    if let Some(curr_min_transition) = min_transitions {
-        if transitions.len() == curr_min_transition {
-            // println!("Better path was already found.");
-            return None;
+// A field on the path being explored by `has_path_keys`, with what it takes to carry on from it.
+struct Frame {
+    field: Field,
+    position: Position,
+    directions: Vec<Direction>,
+    next: usize,
+    used_key: bool,
+    // Keys in hand taken by a snare on the field.
+    lost: u16,
+    // The field with the number of keys picked up and doors left open on stepping onto it.
+    state: (Position, usize, usize),
+    // The transition currently being explored from the field.
+    current: Option<Move>,
+    path: Option<Path>,
+    min_cost: Option<usize>,
+}
+
+struct Move {
+    direction: Direction,
+    transition: (Position, Position),
+    // Ticks a trap on the field moved onto holds the path.
+    held: usize,
+    used_door: bool,
+    opened_door: bool,
+}
+
+// Takes one off the count kept for `key`, forgetting it at zero.
+fn leave<K: Eq + std::hash::Hash>(counts: &mut HashMap<K, u32>, key: K) {
+    if let Some(n) = counts.get_mut(&key) {
+        *n -= 1;
+        if *n == 0 {
+            counts.remove(&key);
         }
     }
-    let mut used_key = false;
-    if safe_has_key(Arc::clone(&f1)) {
-        if keys.add(Arc::clone(&f1)) {
-            used_key = true;
-            // println!("keys: {} (+1)", keys.total);
+}
+
+// Depth-first search for the shortest path, keeping the path being explored on an explicit stack
+// so that long paths don't overflow the thread's stack.
+fn has_path_keys(f1: Field, ends: &[Field], keys: &mut Keys, rules: &Rules, probe: &mut Probe) -> Option<Path> {
+    let targets: Vec<Position> = ends.iter().map(|f| safe_position(Arc::clone(f))).collect();
+    // Transitions on the path, by the positions they connect; none of them may be taken twice.
+    let mut transitions: HashSet<(Position, Position)> = HashSet::new();
+    // How often every field is on the path, and every field with the keys picked up and doors opened
+    // when the path got there. Coming back to a field without having picked up a key or opened a door
+    // since can only leave fewer keys in hand, so the loop is never part of a shortest path.
+    let mut on_path: HashMap<Position, u32> = HashMap::new();
+    let mut states: HashMap<(Position, usize, usize), u32> = HashMap::new();
+    let mut stack: Vec<Frame> = Vec::new();
+    // Cost of the path so far: its transitions and the ticks traps on it hold it for.
+    let mut spent = 0;
+    let mut entering: Option<(Field, Option<usize>)> = Some((f1, None));
+    loop {
+        let done: Option<(Path, usize)>;
+        if let Some((f, min_cost)) = entering.take() {
+            let position = safe_position(Arc::clone(&f));
+            let depth = spent;
+            probe.record(|| Event::ENTER(position));
+            if let Some(stats) = probe.stats.as_deref_mut() {
+                stats.max_depth = stats.max_depth.max(transitions.len());
+            }
+            if targets.contains(&position) {
+                probe.record(|| Event::FOUND(depth));
+                probe.record(|| Event::BACKTRACK(position));
+                done = Some((Path { steps: Vec::new() }, depth));
+            } else if min_cost.is_some_and(|m| depth >= m) || states.contains_key(&(position, keys.fields.len(), keys.opened.len())) {
+                // Better path was already found, or the path came back without gaining anything.
+                if let Some(stats) = probe.stats.as_deref_mut() {
+                    stats.pruned += 1;
+                }
+                probe.record(|| Event::BACKTRACK(position));
+                done = None;
+            } else {
+                if let Some(stats) = probe.stats.as_deref_mut() {
+                    stats.expanded += 1;
+                }
+                let mut lost = 0;
+                if let Some(Hazard::SNARE(n)) = safe_hazard(Arc::clone(&f)) {
+                    while lost < n && keys.remove_use() {
+                        lost += 1;
+                    }
+                }
+                let used_key = safe_has_key(Arc::clone(&f)) && keys.add(Arc::clone(&f));
+                if lost > 0 {
+                    probe.record(|| Event::SNARE(position, lost));
+                }
+                if used_key {
+                    probe.record(|| Event::KEY(position));
+                }
+                let directions = directions_heuristic(Arc::clone(&f), &targets, rules.grid.topology());
+                let state = (position, keys.fields.len(), keys.opened.len());
+                *on_path.entry(position).or_insert(0) += 1;
+                *states.entry(state).or_insert(0) += 1;
+                stack.push(Frame { field: f, position, directions, next: 0, used_key, lost, state, current: None, path: None, min_cost });
+                continue;
+            }
         } else {
-            // println!("key already used.");
-        }
-    }
-    // println!("keys: {}", keys.total);
-    let directions = directions_heuristic(Arc::clone(&f1), Arc::clone(&f2));
-    let mut path: Option<Path> = None;
-    for d in directions {
-        let t_pos = safe_get_transition(Arc::clone(&f1), d);
-        if t_pos.is_none() {
-            continue;
-        }
-        // println!("going {:?}", d);
-        let t_ptr = t_pos.unwrap();
-        // v.iter().any(|e| e == "hello")
-        if !transitions.iter().any(|e| safe_equals_t(Arc::clone(&e), Arc::clone(&t_ptr))) {
-            let t = t_ptr.lock().unwrap();
-            let (doors, f) = (t.doors, Arc::clone(&t.get_field2()));
-            drop(t);
-            if doors {
-                if keys.remove_use() {
-                    // println!("keys: {} (-1)", keys.total);
-                } else {
-                    // println!("no keys left!");
+            let frame = stack.last_mut().unwrap();
+            if let Some(d) = frame.directions.get(frame.next).copied() {
+                frame.next += 1;
+                let Some(t_ptr) = safe_get_transition(Arc::clone(&frame.field), d) else {
+                    continue;
+                };
+                let t = t_ptr.lock().unwrap();
+                let (doors, f) = (t.has_doors(), t.get_field2());
+                drop(t);
+                let transition = (frame.position, safe_position(Arc::clone(&f)));
+                if transitions.contains(&transition) {
                     continue;
                 }
-            }
-            transitions.push(Arc::clone(&t_ptr));
-            // println!("transitions expanded to: {:?}", transitions);
-            if let Some((mut steps, new_min_transitions)) = has_path_keys(f, Arc::clone(&f2), keys, transitions, min_transitions) {
-                if let Some(curr_path) = &path {
-                    if steps.cost() + 1 < curr_path.cost() {
-                        steps.add_step(d);
-                        path = Some(steps);
-                        min_transitions = Some(new_min_transitions);
+                let held = match safe_hazard(Arc::clone(&f)) {
+                    Some(Hazard::FORBIDDEN) => continue,
+                    // Collapsed behind the path already.
+                    Some(Hazard::BRIDGE) if on_path.contains_key(&transition.1) => continue,
+                    Some(Hazard::TRAP(n)) => usize::from(n),
+                    _ => 0,
+                };
+                let (mut used_door, mut opened_door) = (false, false);
+                if doors {
+                    if rules.doors_stay_open && keys.is_open(Arc::clone(&t_ptr)) {
+                        // Doors already open.
+                    } else if keys.remove_use() {
+                        used_door = true;
+                        probe.record(|| Event::DOOR(frame.position, d));
+                        if rules.doors_stay_open {
+                            keys.open(t_ptr);
+                            opened_door = true;
+                        }
+                    } else {
+                        // No keys left.
+                        if let Some(stats) = probe.stats.as_deref_mut() {
+                            stats.blocked += 1;
+                        }
+                        continue;
                     }
-                } else {
-                    steps.add_step(d);
-                    path = Some(steps);
-                    min_transitions = Some(new_min_transitions);
                 }
+                transitions.insert(transition);
+                spent += 1 + held;
+                frame.current = Some(Move { direction: d, transition, held, used_door, opened_door });
+                entering = Some((f, frame.min_cost));
+                continue;
+            }
+            let frame = stack.pop().unwrap();
+            leave(&mut on_path, frame.position);
+            leave(&mut states, frame.state);
+            if frame.used_key {
+                keys.remove();
             }
-            let rt = transitions.pop().unwrap();
-            let lrt = rt.lock().unwrap();
-            // println!("Removed: {}", lrt);
-            drop(lrt);
-            if doors {
+            for _ in 0..frame.lost {
                 keys.add_use();
             }
-        } else {
-            // println!("Not adding transition: {:?} because it's been already explored.", t_ptr.borrow());
+            probe.record(|| Event::BACKTRACK(frame.position));
+            done = frame.path.map(|p| (p, frame.min_cost.unwrap()));
+        }
+
+        // Hand the result over to the field the search came from and step back to it.
+        let Some(parent) = stack.last_mut() else {
+            return done.map(|(path, _)| path);
+        };
+        let m = parent.current.take().unwrap();
+        if let Some((mut steps, new_min_cost)) = done {
+            if parent.path.as_ref().is_none_or(|p| steps.cost() + 1 + m.held < p.cost()) {
+                for _ in 0..m.held {
+                    steps.add_step(Direction::WAIT);
+                }
+                steps.add_step(m.direction);
+                parent.path = Some(steps);
+                parent.min_cost = Some(new_min_cost);
+            }
+        }
+        transitions.remove(&m.transition);
+        spent -= 1 + m.held;
+        if m.used_door {
+            keys.add_use();
+        }
+        if m.opened_door {
+            keys.close();
         }
     }
-    if used_key {
-        keys.remove();
-        // println!("keys: {} (-1)", keys.total);
-    }
-    return path.and_then(|p| Some((p, min_transitions.unwrap())));
 }
 
 #[cfg(test)]
 mod test {
     use std::{sync::{Arc, Mutex}};
 
-    use crate::maze::{has_path, min_path};
+    use crate::{maze::{has_path, has_path_rules, has_path_stats, min_path, min_path_rules, min_path_stats, Mode, Rules, Stats}, reader::read_maze};
+
+    use super::{SimpleField, Direction, Transition, Field, Hazard, Maze};
 
-    use super::{SimpleField, Direction, Transition, Field};
+    fn field(x: u8, y: u8, key: bool, end: bool) -> Field {
+        Arc::new(Mutex::new(SimpleField::new(x, y, key, end)))
+    }
 
     fn tie_graph(a: &[Field]) {
         Transition::new(true, &Direction::EAST, Arc::clone(&a[0]), Arc::clone(&a[1]));
@@ -468,6 +1159,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn basics() {
         let f1 = SimpleField::new(0, 0, true, false);
         assert_eq!(f1.has_key(), true);
@@ -512,6 +1204,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison, clippy::vec_init_then_push)]
     fn not_closest() {
         let f1 = SimpleField::new(0, 0, false, false);
         let rf1 = Arc::new(Mutex::new(f1));
@@ -551,4 +1244,265 @@ mod test {
             pp.print_path();
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn doors_stay_open() {
+        let rf1 = Arc::new(Mutex::new(SimpleField::new(0, 0, true, false)));
+        let rf2 = Arc::new(Mutex::new(SimpleField::new(1, 0, true, false)));
+        let rf3 = Arc::new(Mutex::new(SimpleField::new(2, 0, true, false)));
+        let rf4 = Arc::new(Mutex::new(SimpleField::new(0, 1, false, false)));
+        let rf5 = Arc::new(Mutex::new(SimpleField::new(0, 2, false, true)));
+
+        Transition::new(true, &Direction::EAST, Arc::clone(&rf1), Arc::clone(&rf2));
+        Transition::new(true, &Direction::WEST, Arc::clone(&rf2), Arc::clone(&rf1));
+        Transition::new(false, &Direction::EAST, Arc::clone(&rf2), Arc::clone(&rf3));
+        Transition::new(false, &Direction::WEST, Arc::clone(&rf3), Arc::clone(&rf2));
+        Transition::new(true, &Direction::SOUTH, Arc::clone(&rf1), Arc::clone(&rf4));
+        Transition::new(true, &Direction::SOUTH, Arc::clone(&rf4), Arc::clone(&rf5));
+
+        // Coming back through the east doors costs a key, so only one is left for the two southern doors.
+        let p = has_path(Arc::clone(&rf1), Arc::clone(&rf5));
+        assert!(p.is_none());
+
+        let rules = Rules { doors_stay_open: true, ..Rules::default() };
+        let p = has_path_rules(Arc::clone(&rf1), Arc::clone(&rf5), rules);
+        assert!(p.is_some());
+        assert_eq!(p.unwrap().cost(), 6);
+    }
+
+    #[test]
+    fn portal() {
+        let rf1 = field(0, 0, false, false);
+        let rf2 = field(1, 0, false, false);
+        let rf3 = field(5, 5, false, false);
+        let rf4 = field(5, 6, false, true);
+
+        Transition::new(false, &Direction::EAST, Arc::clone(&rf1), Arc::clone(&rf2));
+        Transition::new(false, &Direction::PORTAL, Arc::clone(&rf2), Arc::clone(&rf3));
+        Transition::new(false, &Direction::PORTAL, Arc::clone(&rf3), Arc::clone(&rf2));
+        Transition::new(false, &Direction::SOUTH, Arc::clone(&rf3), Arc::clone(&rf4));
+
+        let p = has_path(Arc::clone(&rf1), Arc::clone(&rf4));
+        assert!(p.is_some());
+        let p = p.unwrap();
+        assert_eq!(p.cost(), 3);
+        assert_eq!(p.to_string(), "EAST -> PORTAL -> SOUTH");
+    }
+
+    #[test]
+    fn floors() {
+        let rf1 = Arc::new(Mutex::new(SimpleField::new_3d(0, 0, 0, false, false)));
+        let rf2 = Arc::new(Mutex::new(SimpleField::new_3d(0, 0, 1, false, false)));
+        let rf3 = Arc::new(Mutex::new(SimpleField::new_3d(1, 0, 1, false, false)));
+        let rf4 = Arc::new(Mutex::new(SimpleField::new_3d(1, 0, 0, false, true)));
+
+        // Same column and row on different floors are different fields.
+        assert!(*rf1.lock().unwrap() != *rf2.lock().unwrap());
+        assert_eq!(rf3.lock().unwrap().to_string(), "(1,0,1)");
+
+        Transition::new(false, &Direction::UP, Arc::clone(&rf1), Arc::clone(&rf2));
+        Transition::new(false, &Direction::EAST, Arc::clone(&rf2), Arc::clone(&rf3));
+        Transition::new(false, &Direction::DOWN, Arc::clone(&rf3), Arc::clone(&rf4));
+
+        let p = has_path(Arc::clone(&rf1), Arc::clone(&rf4));
+        assert!(p.is_some());
+        assert_eq!(p.unwrap().to_string(), "UP -> EAST -> DOWN");
+    }
+
+    #[test]
+    fn stats() {
+        let a: Vec<Field> = vec![field(0, 0, false, false), field(1, 0, false, false), field(0, 1, false, false), field(1, 1, false, true)];
+        tie_graph(&a);
+
+        // Both ways out of the start are doors and there are no keys.
+        let mut stats = Stats::default();
+        assert!(has_path_stats(Arc::clone(&a[0]), Arc::clone(&a[3]), Rules::default(), &mut stats).is_none());
+        assert_eq!(stats.expanded, 1);
+        assert_eq!(stats.blocked, 2);
+        assert_eq!(stats.max_depth, 0);
+
+        a[0].lock().unwrap().key = true;
+        let (p, stats) = min_path_stats(Arc::clone(&a[0]), vec![Arc::clone(&a[3])], Mode::SERIAL, Rules::default());
+        assert_eq!(p.unwrap().cost(), 2);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.timings.len(), 1);
+        assert!(stats.to_string().starts_with("Fields expanded: "));
+    }
+
+    #[test]
+    fn long_corridor() {
+        // A winding one-way corridor through a 200x100 grid, so the only path has 19999 steps.
+        // With transitions back the search would walk all the way back from every field.
+        let (width, height) = (200u8, 100u8);
+        let mut corridor: Vec<Field> = Vec::new();
+        for y in 0..height {
+            for i in 0..width {
+                let x = if y % 2 == 0 { i } else { width - 1 - i };
+                corridor.push(field(x, y, false, false));
+            }
+        }
+        for pair in corridor.windows(2) {
+            let (p1, p2) = (pair[0].lock().unwrap().position(), pair[1].lock().unwrap().position());
+            let d = if p1.1 != p2.1 {
+                Direction::SOUTH
+            } else if p1.0 < p2.0 {
+                Direction::EAST
+            } else {
+                Direction::WEST
+            };
+            Transition::new(false, &d, Arc::clone(&pair[0]), Arc::clone(&pair[1]));
+        }
+        let end = Arc::clone(corridor.last().unwrap());
+        end.lock().unwrap().end = true;
+
+        let p = min_path(Arc::clone(&corridor[0]), vec![end], Mode::PARALLEL);
+        assert_eq!(p.unwrap().cost(), corridor.len() - 1);
+    }
+
+    #[test]
+    fn two_way_corridor() {
+        // The same winding corridor open both ways: every step back leads to a field already on the path
+        // without anything gained, so the search turns round right there.
+        let (width, height) = (200u8, 100u8);
+        let mut corridor: Vec<Field> = Vec::new();
+        for y in 0..height {
+            for i in 0..width {
+                let x = if y % 2 == 0 { i } else { width - 1 - i };
+                corridor.push(field(x, y, false, false));
+            }
+        }
+        for pair in corridor.windows(2) {
+            let (p1, p2) = (pair[0].lock().unwrap().position(), pair[1].lock().unwrap().position());
+            let (there, back) = if p1.1 != p2.1 {
+                (Direction::SOUTH, Direction::NORTH)
+            } else if p1.0 < p2.0 {
+                (Direction::EAST, Direction::WEST)
+            } else {
+                (Direction::WEST, Direction::EAST)
+            };
+            Transition::new(false, &there, Arc::clone(&pair[0]), Arc::clone(&pair[1]));
+            Transition::new(false, &back, Arc::clone(&pair[1]), Arc::clone(&pair[0]));
+        }
+        let end = Arc::clone(corridor.last().unwrap());
+        end.lock().unwrap().end = true;
+
+        let (p, stats) = min_path_stats(Arc::clone(&corridor[0]), vec![end], Mode::SERIAL, Rules::default());
+        assert_eq!(p.unwrap().cost(), corridor.len() - 1);
+        assert_eq!(stats.expanded as usize, corridor.len() - 1);
+    }
+
+    #[test]
+    fn per_exit() {
+        let a: Vec<Field> = vec![field(0, 0, true, false), field(1, 0, false, true), field(0, 1, false, true), field(1, 1, false, true)];
+        tie_graph(&a);
+        let ends: Vec<Field> = a[1..].iter().map(Arc::clone).collect();
+
+        // One search for all exits finds the same shortest path as a search for every exit.
+        let (p, stats) = min_path_stats(Arc::clone(&a[0]), ends.clone(), Mode::SERIAL, Rules::default());
+        assert_eq!(p.unwrap().cost(), 1);
+        assert_eq!(stats.timings.len(), 1);
+        let rules = Rules { per_exit: true, ..Rules::default() };
+        let (p, stats) = min_path_stats(Arc::clone(&a[0]), ends, Mode::PARALLEL, rules);
+        assert_eq!(p.unwrap().cost(), 1);
+        assert_eq!(stats.timings.len(), 3);
+    }
+
+    #[test]
+    fn editing() {
+        // (0,0) k   (1,0)   (2,0) end, with a portal from (0,0) to (2,1) and every wall up.
+        let mut maze = read_maze("SQUARE 3 2\n0000 0000 0000\n0000 0000 0000\n0000 0000 0011\n0000 0000 0000\n0000 0000 0000\n0000 0000 0000\nP 0 0 2 1\n").unwrap();
+        let doors = |maze: &Maze, p, d| maze.get(p).unwrap().lock().unwrap().get_transition(d).map(|t| t.lock().unwrap().has_doors());
+        let end = maze.get((2, 0, 0)).unwrap();
+
+        assert!(maze.set_wall((0, 0, 0), Direction::EAST, false));
+        assert!(!maze.set_wall((0, 0, 0), Direction::EAST, false));
+        assert!(maze.set_wall((2, 0, 0), Direction::WEST, false));
+        assert!(!maze.set_wall((0, 0, 0), Direction::WEST, false));
+        assert_eq!(doors(&maze, (1, 0, 0), Direction::WEST), Some(false));
+        assert_eq!(has_path(maze.start(), Arc::clone(&end)).unwrap().cost(), 2);
+
+        // Doors go on both sides, and need a key.
+        assert!(maze.set_door((2, 0, 0), Direction::WEST, true));
+        assert!(!maze.set_door((2, 0, 0), Direction::WEST, true));
+        assert_eq!(doors(&maze, (1, 0, 0), Direction::EAST), Some(true));
+        assert!(has_path(maze.start(), Arc::clone(&end)).is_none());
+        assert!(maze.set_key((0, 0, 0), true));
+        assert!(!maze.set_key((0, 0, 0), true));
+        assert_eq!(has_path(maze.start(), Arc::clone(&end)).unwrap().cost(), 2);
+        assert!(maze.toggle_door((1, 0, 0), Direction::EAST));
+        assert_eq!(doors(&maze, (2, 0, 0), Direction::WEST), Some(false));
+        assert!(!maze.set_door((1, 0, 0), Direction::SOUTH, true));
+
+        // Walls and removed transitions leave nothing behind on either side.
+        assert!(maze.set_wall((1, 0, 0), Direction::WEST, true));
+        assert_eq!(doors(&maze, (0, 0, 0), Direction::EAST), None);
+        assert_eq!(doors(&maze, (1, 0, 0), Direction::WEST), None);
+        assert!(maze.set_end((2, 1, 0), true));
+        assert_eq!(maze.ends().len(), 2);
+        assert_eq!(has_path(maze.start(), maze.get((2, 1, 0)).unwrap()).unwrap().to_string(), "PORTAL");
+        assert!(maze.remove_transition((2, 1, 0), Direction::PORTAL));
+        assert_eq!(doors(&maze, (0, 0, 0), Direction::PORTAL), None);
+        assert!(!maze.remove_transition((0, 0, 0), Direction::PORTAL));
+        assert!(!maze.set_key((3, 0, 0), true));
+        assert_eq!(maze.changes_since(0).len(), 14);
+    }
+
+    #[test]
+    fn hazards() {
+        // (0,0) --- (1,0) --- (2,0) k
+        //   |
+        //  -|- (0,1) end
+        let content = "SQUARE 3 2\n0101 0001 0000\n1100 0000 0000\n1000 0000 1100\n0010 0010 0011\n0000 0000 0000\n0000 0000 0000\n";
+        let mut maze = read_maze(content).unwrap();
+        assert_eq!(min_path(maze.start(), maze.ends(), Mode::SERIAL).unwrap().to_string(), "EAST -> EAST -> WEST -> WEST -> SOUTH");
+        // Crossing the bridge a second time, losing the key on the way back or not getting to it at all.
+        for hazard in [Hazard::BRIDGE, Hazard::SNARE(1), Hazard::FORBIDDEN] {
+            assert!(maze.set_hazard((1, 0, 0), Some(hazard)));
+            assert!(min_path(maze.start(), maze.ends(), Mode::SERIAL).is_none(), "{:?} didn't get in the way", hazard);
+        }
+        // Traps only slow the walk down, every time it steps on them.
+        maze.set_hazard((1, 0, 0), Some(Hazard::TRAP(2)));
+        let p = min_path(maze.start(), maze.ends(), Mode::SERIAL).unwrap();
+        assert_eq!(p.to_string(), "EAST -> WAIT -> WAIT -> EAST -> WEST -> WAIT -> WAIT -> WEST -> SOUTH");
+        assert_eq!(p.cost(), 5 + 2 * 2);
+        assert!(!maze.set_hazard((1, 0, 0), Some(Hazard::TRAP(2))));
+        assert_eq!(maze.changes_since(0).len(), 4);
+    }
+
+    #[test]
+    fn capacity() {
+        // (0,0) k --- (1,0) k -|- (2,0) -|- (3,0) end
+        //                            |          |
+        //                          (2,1) k --- (3,1)
+        // The way down and round is the one with a key between the doors.
+        let mut content = String::from("SQUARE 4 2\n0100 0000 1100\n1100 0100 1100\n1101 1100 0000\n1001 1001 0011\n");
+        content.push_str("0000 0000 0000\n0000 0000 0000\n0110 0000 1100\n1010 0010 0000\n");
+        let maze = read_maze(&content).unwrap();
+        let search = |rules: Rules| min_path_rules(maze.start(), maze.ends(), Mode::SERIAL, rules);
+        assert_eq!(search(Rules::default()).unwrap().cost(), 3);
+        assert_eq!(search(Rules { capacity: Some(2), ..Rules::default() }).unwrap().cost(), 3);
+        // The key on (1,0) is left lying, and one key in hand takes only one door at a time.
+        let rules = Rules { capacity: Some(1), ..Rules::default() };
+        assert_eq!(search(rules).unwrap().to_string(), "EAST -> EAST -> SOUTH -> EAST -> NORTH");
+        let (p, stats) = min_path_stats(maze.start(), maze.ends(), Mode::SERIAL, rules);
+        assert_eq!(p.unwrap().cost(), 5);
+        assert!(stats.expanded >= 5);
+        assert!(search(Rules { capacity: Some(0), ..Rules::default() }).is_none());
+
+        // No transition is taken twice, so with one key in hand there is no going down the dead end
+        // again for the second key once the first has opened the doors.
+        // (0,0) --- (1,0) -|- (2,0) -|- (3,0) end
+        //   |
+        // (0,1) k
+        //   |
+        // (0,2) k
+        let mut content = String::from("SQUARE 4 3\n0101 0000 0000\n1100 0100 0000\n1100 1100 0000\n1000 1000 0011\n");
+        content.push_str("0011 0000 1100\n0000 0000 0000\n0000 0000 0000\n0000 0000 0000\n");
+        content.push_str("0010 0000 1100\n0000 0000 0000\n0000 0000 0000\n0000 0000 0000\n");
+        let maze = read_maze(&content).unwrap();
+        let search = |rules: Rules| min_path_rules(maze.start(), maze.ends(), Mode::SERIAL, rules);
+        assert_eq!(search(Rules { capacity: Some(2), ..Rules::default() }).unwrap().cost(), 7);
+        let rules = Rules { capacity: Some(1), doors_stay_open: true, ..Rules::default() };
+        assert!(search(rules).is_none());
+    }
+}
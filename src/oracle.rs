@@ -0,0 +1,154 @@
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
+use crate::maze::{refuse_hazards, safe_neighbours, safe_position, Direction, Field, Hazard, Maze, Path, Position};
+
+const UNREACHABLE: u32 = u32::MAX;
+
+/// Most fields a maze may have for the oracle to build its table, taking 128 MiB at that size.
+pub const MAX_FIELDS: usize = 4096;
+
+/// Precomputed distances between every two fields of a maze, walking only through transitions
+/// without doors, so that repeated queries don't have to search the maze again.
+///
+/// Takes memory quadratic in the number of fields, so it refuses mazes of more than `MAX_FIELDS`.
+/// Every step costs the same, so it refuses mazes with traps too.
+/// The table describes the maze as it was when it was built: check `is_stale` or call `refresh`
+/// after changing the maze.
+pub struct Oracle {
+    index: HashMap<Position, usize>,
+    // Moves without doors out of every field.
+    moves: Vec<Vec<(Direction, usize)>>,
+    // Row `i` holds the distances from field `i` and the first field on a shortest path to each field.
+    distance: Vec<u32>,
+    next: Vec<u32>,
+    // Revision of the maze the table was built for.
+    revision: usize,
+}
+
+impl Oracle {
+    /// Returns an error if the maze has more than `MAX_FIELDS` fields or has traps.
+    pub fn new(maze: &Maze) -> Result<Self, String> {
+        let fields = maze.fields();
+        let n = fields.len();
+        if n > MAX_FIELDS {
+            return Err(format!("At most {} fields fit into the table, the maze has {}.", MAX_FIELDS, n));
+        }
+        refuse_hazards(fields, "The distance table", |h| matches!(h, Hazard::TRAP(_)))?;
+        let index: HashMap<Position, usize> = fields.iter().enumerate().map(|(i, f)| (safe_position(Arc::clone(f)), i)).collect();
+        let moves: Vec<Vec<(Direction, usize)>> = fields.iter().map(|f| {
+            safe_neighbours(Arc::clone(f)).into_iter()
+                .filter(|(_, doors, _)| !doors)
+                .filter_map(|(d, _, f2)| index.get(&safe_position(f2)).map(|to| (d, *to)))
+                .collect()
+        }).collect();
+
+        let mut distance = vec![UNREACHABLE; n * n];
+        let mut next = vec![UNREACHABLE; n * n];
+        for s in 0..n {
+            let row = s * n;
+            distance[row + s] = 0;
+            next[row + s] = s as u32;
+            let mut queue = VecDeque::from([s]);
+            while let Some(u) = queue.pop_front() {
+                for (_, v) in &moves[u] {
+                    if distance[row + v] != UNREACHABLE {
+                        continue;
+                    }
+                    distance[row + v] = distance[row + u] + 1;
+                    // The first step towards `v` is the one towards the field it was reached from.
+                    next[row + v] = if u == s { *v as u32 } else { next[row + u] };
+                    queue.push_back(*v);
+                }
+            }
+        }
+        Ok(Oracle { index, moves, distance, next, revision: maze.revision() })
+    }
+
+    fn cell(&self, f1: &Field, f2: &Field) -> Option<(usize, usize)> {
+        let i = *self.index.get(&safe_position(Arc::clone(f1)))?;
+        let j = *self.index.get(&safe_position(Arc::clone(f2)))?;
+        Some((i, j))
+    }
+
+    /// Number of steps on the shortest path from `f1` to `f2` without doors, or `None` if there is none.
+    pub fn distance(&self, f1: &Field, f2: &Field) -> Option<usize> {
+        let (i, j) = self.cell(f1, f2)?;
+        let d = self.distance[i * self.moves.len() + j];
+        (d != UNREACHABLE).then_some(d as usize)
+    }
+
+    /// Shortest path from `f1` to `f2` without doors, built in time proportional to its length.
+    pub fn path(&self, f1: &Field, f2: &Field) -> Option<Path> {
+        let (mut i, j) = self.cell(f1, f2)?;
+        let n = self.moves.len();
+        if self.distance[i * n + j] == UNREACHABLE {
+            return None;
+        }
+        let mut steps = Vec::new();
+        while i != j {
+            let to = self.next[i * n + j] as usize;
+            let (d, _) = self.moves[i].iter().find(|(_, t)| *t == to)?;
+            steps.push(*d);
+            i = to;
+        }
+        Some(Path::from_steps(steps))
+    }
+
+    /// Whether the maze was changed through its methods since the table was built. Changes made to
+    /// the fields directly go unnoticed.
+    pub fn is_stale(&self, maze: &Maze) -> bool {
+        maze.revision() != self.revision
+    }
+
+    /// Builds the table again if the maze changed, returning whether it did.
+    pub fn refresh(&mut self, maze: &Maze) -> Result<bool, String> {
+        if !self.is_stale(maze) {
+            return Ok(false);
+        }
+        *self = Oracle::new(maze)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{maze::{Direction, Hazard}, reader::read_maze};
+
+    use super::{Oracle, MAX_FIELDS};
+
+    #[test]
+    fn distances_and_paths() {
+        // (0,0) --- (1,0) -|- (2,0)
+        //   |
+        // (0,1) --- (1,1)     (2,1)
+        let mut maze = read_maze("SQUARE 3 2\n0101 0000 0000\n1100 0100 0000\n1000 1000 0000\n0110 0000 0000\n1000 0000 0000\n0000 0000 0000\n").unwrap();
+        let mut oracle = Oracle::new(&maze).unwrap();
+        let f = |x, y| maze.get((x, y, 0)).unwrap();
+        assert_eq!(oracle.distance(&f(0, 0), &f(0, 0)), Some(0));
+        assert_eq!(oracle.distance(&f(1, 0), &f(1, 1)), Some(3));
+        assert_eq!(oracle.path(&f(1, 0), &f(1, 1)).unwrap().to_string(), "WEST -> SOUTH -> EAST");
+        // Doors and walls both keep fields apart.
+        assert_eq!(oracle.distance(&f(0, 0), &f(2, 0)), None);
+        assert!(oracle.path(&f(0, 0), &f(2, 1)).is_none());
+
+        assert!(!oracle.is_stale(&maze));
+        assert!(maze.add_transition((1, 1, 0), Direction::EAST, (2, 1, 0), false));
+        assert!(oracle.is_stale(&maze));
+        assert!(oracle.refresh(&maze).unwrap());
+        let f = |x, y| maze.get((x, y, 0)).unwrap();
+        assert_eq!(oracle.distance(&f(0, 0), &f(2, 1)), Some(3));
+        assert!(!oracle.refresh(&maze).unwrap());
+        // Every step counts the same in the table, which a trap would change.
+        assert!(maze.set_hazard((1, 1, 0), Some(Hazard::TRAP(1))));
+        assert!(oracle.refresh(&maze).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        // 65 x 64 closed fields, one row more than fits.
+        let mut content = String::from("SQUARE 65 64\n");
+        content.push_str(&"0000 0000 0000\n".repeat(65 * 64));
+        let maze = read_maze(&content).unwrap();
+        assert!(maze.fields().len() > MAX_FIELDS);
+        assert!(Oracle::new(&maze).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::{HashSet, VecDeque};
+use crate::{graph::{Graph, KeyStates}, maze::{Direction, Field, Hazard, Path}};
+
+// Key states of a search together with a virtual sink that every state on an exit leads to,
+// so that paths to different exits are all paths to the same node.
+struct Exits {
+    states: KeyStates,
+    goal: Vec<bool>,
+}
+
+impl Exits {
+    fn new(f1: Field, ends: &[Field]) -> Result<Self, String> {
+        let graph = Graph::new(f1);
+        // Every move costs the same here, which traps would change.
+        graph.refuse_hazards("A search for the shortest paths", |h| matches!(h, Hazard::TRAP(_)))?;
+        let mut end_fields = vec![false; graph.len()];
+        ends.iter().filter_map(|f| graph.index_of(f)).for_each(|i| end_fields[i] = true);
+        let states = KeyStates::new(&graph)?;
+        let goal = (0..states.len()).map(|s| end_fields[states.field(s)]).collect();
+        Ok(Exits { states, goal })
+    }
+
+    fn sink(&self) -> usize {
+        self.states.len()
+    }
+
+    // Moves out of a node; a state on an exit has one extra move, with the last number, to the sink.
+    fn move_count(&self, s: usize) -> usize {
+        if s == self.sink() {
+            return 0;
+        }
+        self.states.moves(s).len() + usize::from(self.goal[s])
+    }
+
+    fn follow(&self, s: usize, m: usize) -> usize {
+        match self.states.moves(s).get(m) {
+            Some((_, to)) => *to,
+            None => self.sink(),
+        }
+    }
+
+    fn path(&self, route: &Route) -> Path {
+        let steps: Vec<Direction> = route.nodes.iter().zip(&route.moves)
+            .filter_map(|(s, m)| self.states.moves(*s).get(*m).map(|(d, _)| *d))
+            .collect();
+        Path::from_steps(steps)
+    }
+
+    // Shortest route from `from` to the sink, skipping banned nodes and moves.
+    fn shortest(&self, from: usize, banned_nodes: &[bool], banned_moves: &HashSet<(usize, usize)>) -> Option<Route> {
+        let mut parents: Vec<Option<(usize, usize)>> = vec![None; self.sink() + 1];
+        let mut seen = vec![false; self.sink() + 1];
+        seen[from] = true;
+        let mut queue = VecDeque::from([from]);
+        while let Some(s) = queue.pop_front() {
+            if s == self.sink() {
+                let mut route = Route { nodes: vec![s], moves: Vec::new() };
+                let mut curr = s;
+                while let Some((prev, m)) = parents[curr] {
+                    route.nodes.push(prev);
+                    route.moves.push(m);
+                    curr = prev;
+                }
+                route.nodes.reverse();
+                route.moves.reverse();
+                return Some(route);
+            }
+            for m in 0..self.move_count(s) {
+                let to = self.follow(s, m);
+                if seen[to] || banned_nodes[to] || banned_moves.contains(&(s, m)) {
+                    continue;
+                }
+                seen[to] = true;
+                parents[to] = Some((s, m));
+                queue.push_back(to);
+            }
+        }
+        None
+    }
+}
+
+// A path through the key states, ending in the sink: `nodes` has one node more than `moves`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Route {
+    nodes: Vec<usize>,
+    moves: Vec<usize>,
+}
+
+impl Route {
+    // Number of steps in the maze, leaving out the move into the sink.
+    fn cost(&self) -> usize {
+        self.moves.len() - 1
+    }
+}
+
+/// Iterator over every shortest path from a field to any of the given exits.
+pub struct MinPaths {
+    exits: Exits,
+    // Distance from every node to the sink, used to only follow moves that stay on a shortest path.
+    to_sink: Vec<Option<usize>>,
+    // Nodes of the path being built with the next move to try from each of them.
+    stack: Vec<(usize, usize)>,
+}
+
+impl Iterator for MinPaths {
+    type Item = Path;
+
+    fn next(&mut self) -> Option<Path> {
+        while let Some((s, m)) = self.stack.pop() {
+            if s == self.exits.sink() {
+                let route = Route {
+                    nodes: self.stack.iter().map(|(s, _)| *s).chain([s]).collect(),
+                    moves: self.stack.iter().map(|(_, m)| m - 1).collect(),
+                };
+                return Some(self.exits.path(&route));
+            }
+            if m == self.exits.move_count(s) {
+                continue;
+            }
+            self.stack.push((s, m + 1));
+            let to = self.exits.follow(s, m);
+            if self.to_sink[to].is_some() && self.to_sink[to].map(|d| d + 1) == self.to_sink[s] {
+                self.stack.push((to, 0));
+            }
+        }
+        None
+    }
+}
+
+/// Every shortest path from `f1` to any field in `ends`, including ties between different exits.
+///
+/// Paths run over the states of `graph::KeyStates`: keys are picked up and spent on doors as in
+/// `has_path`, but a path may come back through a transition it took before once its keys have
+/// changed, so it can be shorter than the one `has_path` finds, or exist where that finds none.
+/// Paths are produced lazily, so taking a few of them is cheap even if there are very many.
+/// Returns an error if more than 64 keys are reachable from `f1`, or a trap or a bridge.
+pub fn all_min_paths(f1: Field, ends: Vec<Field>) -> Result<MinPaths, String> {
+    let exits = Exits::new(f1, &ends)?;
+    let sink = exits.sink();
+    let mut reverse: Vec<Vec<usize>> = vec![Vec::new(); sink + 1];
+    for s in 0..sink {
+        for m in 0..exits.move_count(s) {
+            reverse[exits.follow(s, m)].push(s);
+        }
+    }
+    let mut to_sink = vec![None; sink + 1];
+    to_sink[sink] = Some(0);
+    let mut queue = VecDeque::from([sink]);
+    while let Some(s) = queue.pop_front() {
+        for prev in &reverse[s] {
+            if to_sink[*prev].is_none() {
+                to_sink[*prev] = to_sink[s].map(|d| d + 1);
+                queue.push_back(*prev);
+            }
+        }
+    }
+    let stack = if to_sink[0].is_some() { vec![(0, 0)] } else { Vec::new() };
+    Ok(MinPaths { exits, to_sink, stack })
+}
+
+/// Up to `k` shortest paths from `f1` to any field in `ends`, shortest first.
+///
+/// Uses Yen's algorithm over the key states, so no path goes through the same field twice with the
+/// same keys, but one may come back to a field after picking up a key elsewhere, as in `all_min_paths`.
+/// Returns an error if more than 64 keys are reachable from `f1`, or a trap or a bridge.
+pub fn k_min_paths(f1: Field, ends: Vec<Field>, k: usize) -> Result<Vec<Path>, String> {
+    let exits = Exits::new(f1, &ends)?;
+    let nodes = exits.sink() + 1;
+    let mut found: Vec<Route> = Vec::new();
+    let mut candidates: Vec<Route> = Vec::new();
+    if let Some(route) = exits.shortest(0, &vec![false; nodes], &HashSet::new()) {
+        found.push(route);
+    }
+    while !found.is_empty() && found.len() < k {
+        let last = found.last().unwrap().clone();
+        for i in 0..last.moves.len() {
+            let spur = last.nodes[i];
+            let root = &last.moves[..i];
+            // Moves already taken from the spur node by found paths with the same root are off limits,
+            // and so are the root's nodes, to keep the path from looping back.
+            let banned_moves: HashSet<(usize, usize)> = found.iter()
+                .filter(|r| r.moves.len() > i && &r.moves[..i] == root && r.nodes[..=i] == last.nodes[..=i])
+                .map(|r| (spur, r.moves[i]))
+                .collect();
+            let mut banned_nodes = vec![false; nodes];
+            last.nodes[..i].iter().for_each(|s| banned_nodes[*s] = true);
+            if let Some(tail) = exits.shortest(spur, &banned_nodes, &banned_moves) {
+                let mut route = Route { nodes: last.nodes[..i].to_vec(), moves: root.to_vec() };
+                route.nodes.extend(tail.nodes);
+                route.moves.extend(tail.moves);
+                if !candidates.contains(&route) && !found.contains(&route) {
+                    candidates.push(route);
+                }
+            }
+        }
+        if candidates.is_empty() {
+            break;
+        }
+        let best = (0..candidates.len()).min_by_key(|c| candidates[*c].cost()).unwrap();
+        found.push(candidates.remove(best));
+    }
+    Ok(found.iter().map(|r| exits.path(r)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::maze::{Direction, Field, Hazard, SimpleField, Transition};
+
+    use super::{all_min_paths, k_min_paths};
+
+    fn field(x: u8, y: u8, end: bool) -> Field {
+        Arc::new(Mutex::new(SimpleField::new(x, y, false, end)))
+    }
+
+    fn tie(f1: &Field, f2: &Field, direction: Direction) {
+        Transition::new(false, &direction, Arc::clone(f1), Arc::clone(f2));
+        Transition::new(false, &direction.get_opposite(), Arc::clone(f2), Arc::clone(f1));
+    }
+
+    // A 2x2 square with exits at (1,1) and (2,0), which is one step east of (1,0).
+    fn square() -> (Field, Vec<Field>) {
+        let a = field(0, 0, false);
+        let b = field(1, 0, false);
+        let c = field(0, 1, false);
+        let d = field(1, 1, true);
+        let e = field(2, 0, true);
+        tie(&a, &b, Direction::EAST);
+        tie(&a, &c, Direction::SOUTH);
+        tie(&b, &d, Direction::SOUTH);
+        tie(&c, &d, Direction::EAST);
+        tie(&b, &e, Direction::EAST);
+        (a, vec![d, e])
+    }
+
+    #[test]
+    fn all_shortest() {
+        let (start, ends) = square();
+        let mut paths: Vec<String> = all_min_paths(start, ends).unwrap().map(|p| p.to_string()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["EAST -> EAST", "EAST -> SOUTH", "SOUTH -> EAST"]);
+    }
+
+    #[test]
+    fn k_shortest() {
+        let (start, ends) = square();
+        let paths = k_min_paths(start, ends, 5).unwrap();
+        // Going on from the exit at (1,1) to the other one is the only longer path.
+        let costs: Vec<usize> = paths.iter().map(|p| p.cost()).collect();
+        assert_eq!(costs, vec![2, 2, 2, 4]);
+        assert_eq!(paths[3].to_string(), "SOUTH -> EAST -> NORTH -> EAST");
+    }
+
+    #[test]
+    fn too_many_keys() {
+        let fields: Vec<Field> = (0..65).map(|x| Arc::new(Mutex::new(SimpleField::new(x, 0, true, x == 64)))).collect();
+        fields.windows(2).for_each(|w| tie(&w[0], &w[1], Direction::EAST));
+        assert!(all_min_paths(Arc::clone(&fields[0]), vec![Arc::clone(&fields[64])]).is_err());
+        assert!(k_min_paths(Arc::clone(&fields[0]), vec![Arc::clone(&fields[64])], 2).is_err());
+    }
+
+    #[test]
+    fn hazards() {
+        let (start, ends) = square();
+        let b = start.lock().unwrap().get_transition(Direction::EAST).unwrap().lock().unwrap().get_field2();
+        // Traps make some shortest paths longer than others, and bridges can't be crossed twice.
+        for hazard in [Hazard::TRAP(1), Hazard::BRIDGE] {
+            b.lock().unwrap().set_hazard(Some(hazard));
+            assert!(k_min_paths(Arc::clone(&start), ends.clone(), 2).is_err(), "{:?} was searched through", hazard);
+        }
+        assert!(all_min_paths(Arc::clone(&start), ends.clone()).is_err());
+        b.lock().unwrap().set_hazard(Some(Hazard::FORBIDDEN));
+        let paths: Vec<String> = all_min_paths(start, ends).unwrap().map(|p| p.to_string()).collect();
+        assert_eq!(paths, vec!["SOUTH -> EAST"]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{cell::OnceCell, collections::HashSet};
+use crate::{maze::{min_path_rules, safe_neighbours, safe_position, Direction, Hazard, Maze, Mode, Path, Position, Rules}, trace::draw};
+
+/// A walk through a maze made one move at a time, with keys and doors working as in the key-aware
+/// search: a key is picked up the first time its field is stepped on with room for it in hand, and
+/// every pass through doors takes one, unless the rules keep unlocked doors open. Traps hold the walk
+/// for their ticks, which count as moves, snares take keys, bridges collapse once crossed and
+/// forbidden fields are out of bounds.
+pub struct Game<'a> {
+    maze: &'a Maze,
+    rules: Rules,
+    position: Position,
+    picked: HashSet<Position>,
+    hand: usize,
+    // Doors unlocked so far, by the positions they connect with the smaller one first.
+    opened: HashSet<(Position, Position)>,
+    // Bridges stepped on, collapsed once left.
+    crossed: HashSet<Position>,
+    steps: Vec<Direction>,
+    // Worked out the first time it is asked for.
+    optimal: OnceCell<Option<usize>>,
+}
+
+impl<'a> Game<'a> {
+    /// Starts on the first field of the maze.
+    pub fn new(maze: &'a Maze, rules: Rules) -> Self {
+        let mut game = Game {
+            maze,
+            rules,
+            position: safe_position(maze.start()),
+            picked: HashSet::new(),
+            hand: 0,
+            opened: HashSet::new(),
+            crossed: HashSet::new(),
+            steps: Vec::new(),
+            optimal: OnceCell::new(),
+        };
+        game.pick_up();
+        game
+    }
+
+    fn hazard(&self, p: Position) -> Option<Hazard> {
+        self.maze.get(p).and_then(|f| f.lock().unwrap().get_hazard())
+    }
+
+    // Whatever happens on stepping on the current field, key pick-up last.
+    fn pick_up(&mut self) {
+        match self.hazard(self.position) {
+            Some(Hazard::TRAP(n)) if !self.steps.is_empty() => self.steps.extend(std::iter::repeat_n(Direction::WAIT, usize::from(n))),
+            Some(Hazard::SNARE(n)) => self.hand = self.hand.saturating_sub(usize::from(n)),
+            Some(Hazard::BRIDGE) => {
+                self.crossed.insert(self.position);
+            },
+            _ => {},
+        }
+        let key = self.maze.get(self.position).is_some_and(|f| f.lock().unwrap().has_key());
+        let room = self.rules.capacity.is_none_or(|c| self.hand < usize::from(c));
+        if key && room && self.picked.insert(self.position) {
+            self.hand += 1;
+        }
+    }
+
+    /// Moves in `direction`, or explains why that isn't possible.
+    pub fn step(&mut self, direction: Direction) -> Result<(), String> {
+        if self.is_won() {
+            return Err(String::from("You are already out."));
+        }
+        let t = self.maze.get(self.position).and_then(|f| f.lock().unwrap().get_transition(direction));
+        let Some(t) = t else {
+            return Err(format!("There is no way {:?}.", direction));
+        };
+        let lt = t.lock().unwrap();
+        let (doors, to) = (lt.has_doors(), safe_position(lt.get_field2()));
+        drop(lt);
+        match self.hazard(to) {
+            Some(Hazard::FORBIDDEN) => return Err(format!("The way {:?} leads to a forbidden field.", direction)),
+            Some(Hazard::BRIDGE) if self.crossed.contains(&to) => return Err(String::from("The bridge has collapsed.")),
+            _ => {},
+        }
+        if doors {
+            let pair = (self.position.min(to), self.position.max(to));
+            if !(self.rules.doors_stay_open && self.opened.contains(&pair)) {
+                if self.hand == 0 {
+                    return Err(String::from("The doors are locked and you have no keys."));
+                }
+                self.hand -= 1;
+                if self.rules.doors_stay_open {
+                    self.opened.insert(pair);
+                }
+            }
+        }
+        self.position = to;
+        self.steps.push(direction);
+        self.pick_up();
+        Ok(())
+    }
+
+    /// Ways out of the current field, as far as can be seen from it: where they go and whether they have doors.
+    pub fn ways(&self) -> Vec<(Direction, bool)> {
+        self.maze.get(self.position).map_or(Vec::new(), |f| safe_neighbours(f).into_iter().map(|(d, doors, _)| (d, doors)).collect())
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Keys picked up and not spent yet.
+    pub fn keys(&self) -> usize {
+        self.hand
+    }
+
+    pub fn moves(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Cost of the shortest way out, or `None` if there is no way out.
+    pub fn optimal(&self) -> Option<usize> {
+        *self.optimal.get_or_init(|| min_path_rules(self.maze.start(), self.maze.ends(), Mode::SERIAL, self.rules).map(|p| p.cost()))
+    }
+
+    pub fn is_won(&self) -> bool {
+        self.maze.get(self.position).is_some_and(|f| f.lock().unwrap().is_end())
+    }
+
+    /// Moves made so far.
+    pub fn path(&self) -> Path {
+        Path::from_steps(self.steps.clone())
+    }
+
+    /// The maze with `@` on the current field, `k` on keys not picked up yet, `E` on exits, `T` on
+    /// traps, `S` on snares, `=` on bridges still standing and `X` on forbidden fields, followed by
+    /// the number of moves and keys.
+    pub fn draw(&self) -> String {
+        let map = draw(self.maze, |p| {
+            if p == self.position {
+                return '@';
+            }
+            match self.maze.get(p) {
+                Some(f) if f.lock().unwrap().has_key() && !self.picked.contains(&p) => 'k',
+                Some(f) if f.lock().unwrap().is_end() => 'E',
+                _ => match self.hazard(p) {
+                    Some(Hazard::TRAP(_)) => 'T',
+                    Some(Hazard::SNARE(_)) => 'S',
+                    Some(Hazard::BRIDGE) if !self.crossed.contains(&p) => '=',
+                    Some(Hazard::FORBIDDEN) => 'X',
+                    _ => ' ',
+                },
+            }
+        });
+        format!("{}Moves: {}  Keys: {}\n", map, self.moves(), self.hand)
+    }
+
+    /// How the walk compares with the shortest way out.
+    pub fn summary(&self) -> String {
+        match self.optimal() {
+            Some(best) if self.is_won() && self.moves() == best => format!("Out in {} moves, as fast as it gets.", self.moves()),
+            Some(best) if self.is_won() => format!("Out in {} moves, the shortest way takes {}.", self.moves(), best),
+            Some(best) => format!("{} moves so far, the shortest way out takes {}.", self.moves(), best),
+            None => format!("{} moves so far, there is no way out.", self.moves()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{maze::{Direction, Rules}, reader::read_maze};
+
+    use super::Game;
+
+    #[test]
+    fn walk_out() {
+        // (0,0) --- (1,0) k -|- (2,0) end
+        let maze = read_maze("SQUARE 3 1\n0100 0000 0000\n1100 0100 1100\n1000 1000 0011\n").unwrap();
+        let mut game = Game::new(&maze, Rules::default());
+        assert_eq!(game.optimal(), Some(2));
+        assert!(game.step(Direction::WEST).is_err());
+        assert!(game.step(Direction::EAST).is_ok());
+        assert_eq!(game.keys(), 1);
+        assert!(game.draw().starts_with("+---+---+---+\n|     @ : E |\n"));
+
+        // Going back and forth doesn't hand out the key again.
+        game.step(Direction::WEST).unwrap();
+        game.step(Direction::EAST).unwrap();
+        assert_eq!(game.keys(), 1);
+        game.step(Direction::EAST).unwrap();
+        assert_eq!(game.keys(), 0);
+        assert_eq!(game.step(Direction::WEST), Err(String::from("You are already out.")));
+        assert!(game.is_won());
+        assert_eq!(game.path().to_string(), "EAST -> WEST -> EAST -> EAST");
+        assert_eq!(game.summary(), "Out in 4 moves, the shortest way takes 2.");
+    }
+
+    #[test]
+    fn doors_stay_open() {
+        let maze = read_maze("SQUARE 3 1\n0100 0000 0000\n1100 0100 1100\n1000 1000 0000\n").unwrap();
+        let mut game = Game::new(&maze, Rules::default());
+        game.step(Direction::EAST).unwrap();
+        game.step(Direction::EAST).unwrap();
+        assert_eq!(game.step(Direction::WEST), Err(String::from("The doors are locked and you have no keys.")));
+        assert_eq!(game.summary(), "2 moves so far, there is no way out.");
+
+        let rules = Rules { doors_stay_open: true, ..Rules::default() };
+        let mut game = Game::new(&maze, rules);
+        game.step(Direction::EAST).unwrap();
+        game.step(Direction::EAST).unwrap();
+        assert!(game.step(Direction::WEST).is_ok());
+        assert_eq!(game.position(), (1, 0, 0));
+    }
+
+    #[test]
+    fn hazards() {
+        // (0,0) --- (1,0) bridge --- (2,0) trap --- (3,0) forbidden
+        let content = "SQUARE 4 1\n0100 0000 0000\n1100 0000 0000\n1100 0000 0000\n1000 0000 0000\nH 1 0 BRIDGE\nH 2 0 TRAP 2\nH 3 0 FORBIDDEN\n";
+        let maze = read_maze(content).unwrap();
+        let mut game = Game::new(&maze, Rules::default());
+        assert!(game.draw().starts_with("+---+---+---+---+\n| @   =   T   X |\n"));
+        game.step(Direction::EAST).unwrap();
+        game.step(Direction::EAST).unwrap();
+        assert_eq!(game.path().to_string(), "EAST -> EAST -> WAIT -> WAIT");
+        assert_eq!(game.step(Direction::EAST), Err(String::from("The way EAST leads to a forbidden field.")));
+        assert_eq!(game.ways(), vec![(Direction::WEST, false)]);
+        assert_eq!(game.step(Direction::WEST), Err(String::from("The bridge has collapsed.")));
+    }
+}
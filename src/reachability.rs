@@ -0,0 +1,108 @@
+use core::fmt;
+use std::{collections::VecDeque, sync::Arc};
+use crate::{graph::Graph, maze::{safe_position, Field, Maze}};
+
+/// How many keys it takes to reach every field of a maze from its start.
+pub struct Reachability {
+    size: (u8, u8, u8),
+    fields: Vec<Field>,
+    // Fewest doors on any route to the field, in the same order as `fields`.
+    keys_needed: Vec<Option<u16>>,
+}
+
+impl Reachability {
+    /// Fewest keys that have to be spent on doors to get to `f`, or `None` if no route leads there.
+    pub fn keys_needed(&self, f: &Field) -> Option<u16> {
+        let p = safe_position(Arc::clone(f));
+        self.fields.iter().position(|e| safe_position(Arc::clone(e)) == p).and_then(|i| self.keys_needed[i])
+    }
+
+    /// Fields that can be reached by spending at most `budget` keys.
+    pub fn reachable_with(&self, budget: u16) -> Vec<Field> {
+        self.fields.iter().zip(&self.keys_needed).filter(|(_, k)| k.is_some_and(|k| k <= budget)).map(|(f, _)| Arc::clone(f)).collect()
+    }
+
+    /// Fields that are walled off from the start, no matter how many keys there are.
+    pub fn unreachable(&self) -> Vec<Field> {
+        self.fields.iter().zip(&self.keys_needed).filter(|(_, k)| k.is_none()).map(|(f, _)| Arc::clone(f)).collect()
+    }
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (width, height, _) = self.size;
+        let n = usize::from(width) * usize::from(height);
+        for (z, floor) in self.keys_needed.chunks(n).enumerate() {
+            if z > 0 {
+                writeln!(f)?;
+            }
+            for row in floor.chunks(usize::from(width)) {
+                let cells: Vec<String> = row.iter().map(|k| match k {
+                    Some(k) => format!("{:>2}", k),
+                    None => String::from(" #"),
+                }).collect();
+                writeln!(f, "{}", cells.join(""))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fewest keys needed to reach every field of the maze from its start.
+///
+/// Every door on the way costs a key, regardless of whether keys can be picked up before it,
+/// so the result tells which parts of the maze open up with which key budget. Forbidden fields are
+/// never reached, and the other hazards can't change the fewest doors on the way to a field.
+pub fn reachability(maze: &Maze) -> Reachability {
+    let graph = Graph::new(maze.start());
+    // 0-1 BFS: walking through a door costs one key, any other transition is free.
+    let mut needed: Vec<Option<u16>> = vec![None; graph.len()];
+    let mut queue = VecDeque::from([(0, 0)]);
+    needed[0] = Some(0);
+    while let Some((i, k)) = queue.pop_front() {
+        if needed[i].is_some_and(|best| best < k) {
+            continue;
+        }
+        for e in graph.edges(i) {
+            let k2 = if e.doors { k + 1 } else { k };
+            if needed[e.to].is_some_and(|best| best <= k2) {
+                continue;
+            }
+            needed[e.to] = Some(k2);
+            if e.doors {
+                queue.push_back((e.to, k2));
+            } else {
+                queue.push_front((e.to, k2));
+            }
+        }
+    }
+
+    let keys_needed = maze.fields().iter().map(|f| graph.index_of(f).and_then(|i| needed[i])).collect();
+    Reachability {
+        size: maze.size(),
+        fields: maze.fields().to_vec(),
+        keys_needed,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::reader::read_maze;
+
+    use super::reachability;
+
+    #[test]
+    fn key_budgets() {
+        // (0,0) -|- (1,0) -|- (2,0)    (3,0) is walled off.
+        let maze = read_maze("SQUARE 4 1\n0100 0100 0000\n1100 1100 0000\n1000 1000 0000\n0000 0000 0000\n").unwrap();
+        let r = reachability(&maze);
+        let fields = maze.fields();
+        assert_eq!(r.keys_needed(&fields[0]), Some(0));
+        assert_eq!(r.keys_needed(&fields[1]), Some(1));
+        assert_eq!(r.keys_needed(&fields[2]), Some(2));
+        assert_eq!(r.keys_needed(&fields[3]), None);
+        assert_eq!(r.reachable_with(1).len(), 2);
+        assert_eq!(r.unreachable().len(), 1);
+        assert_eq!(r.to_string(), " 0 1 2 #\n");
+    }
+}
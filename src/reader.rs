@@ -0,0 +1,257 @@
+use std::sync::{Arc, Mutex};
+use crate::{maze::{Direction, Field, Hazard, Maze, Position, Schedule, SimpleField, Transition, DIRECTIONS}, topology::Grid};
+
+// Size of a maze file without a header line.
+const MAZE_X: u8 = 9;
+const MAZE_Y: u8 = 6;
+
+pub const FORMAT: &str = "The file may start with a header: SQUARE|HEX <width> <height> (default: SQUARE 9 6).\nEvery field is a line of three groups: open sides, doors on those sides and key/end flags,\ne.g. '0101 0100 0011' on a square grid (W E N S) or '010100 010000 0011' on a hex grid (W E NW NE SW SE).\nFloors are grids stacked one after another. After the last grid come the extension lines:\n\tP <x1> <y1> [z1] <x2> <y2> [z2] [0|1]: portal between two fields, at most one per field, with doors on 1.\n\tS <x> <y> <z> [0|1]: stairs from floor z up to floor z + 1, with doors on 1.\n\tT <x> <y> [z] <direction> <period> <from>-<to>...: the way out of a field in a direction, e.g. EAST,\n\t\tand back, has doors that need no key but are open only from tick <from> up to <to>, every <period> ticks\n\t\tor just once with a period of 0. The way may not have doors that take a key.\n\tH <x> <y> [z] TRAP <ticks>|SNARE <keys>|BRIDGE|FORBIDDEN: a field that holds whoever steps on it\n\t\tfor a number of ticks, takes up to a number of keys in hand, can be crossed only once, or can't be stepped on.";
+
+fn read_header(line: &str) -> Result<Option<(Grid, u8, u8)>, String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let grid = match tokens[0] {
+        "SQUARE" => Grid::SQUARE,
+        "HEX" => Grid::HEX,
+        _ => return Ok(None),
+    };
+    let coords = parse_coords(line, &tokens[1..])?;
+    if coords.len() != 2 || coords[0] == 0 || coords[1] == 0 {
+        return Err(format!("Invalid header: {}", line));
+    }
+    Ok(Some((grid, coords[0], coords[1])))
+}
+
+fn parse_coords(line: &str, tokens: &[&str]) -> Result<Vec<u8>, String> {
+    let mut coords: Vec<u8> = Vec::new();
+    for t in tokens {
+        match t.parse::<u8>() {
+            Ok(c) => coords.push(c),
+            Err(_) => return Err(format!("Invalid line: {}", line)),
+        }
+    }
+    Ok(coords)
+}
+
+// Open sides, doors on those sides and key/end flags of a single field.
+type FieldLine<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+// Splits a field line into its groups, checking their lengths.
+fn split_field(line: &str, sides: usize) -> Result<FieldLine<'_>, String> {
+    let groups: Vec<&str> = line.split_whitespace().collect();
+    if groups.len() != 3 || groups[0].len() != sides || groups[1].len() != sides || groups[2].len() != 4 {
+        return Err(format!("Invalid field: {}", line));
+    }
+    Ok((groups[0].as_bytes(), groups[1].as_bytes(), groups[2].as_bytes()))
+}
+
+// Reads the optional doors flag at the end of a `P` or `S` line, which is either `0` or `1`.
+fn parse_doors(line: &str, token: Option<&&str>) -> Result<bool, String> {
+    match token {
+        None | Some(&"0") => Ok(false),
+        Some(&"1") => Ok(true),
+        Some(_) => Err(format!("Invalid line: {}", line)),
+    }
+}
+
+fn get_field(line: &str, maze: &Maze, x: u8, y: u8, z: u8) -> Result<Field, String> {
+    maze.get((x, y, z)).ok_or(format!("Out of the maze: {}", line))
+}
+
+fn tie_extension(extension: &[&str], maze: &Maze) -> Result<(), String> {
+    for line in extension {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match (tokens[0], tokens.len()) {
+            ("P", 5..=8) => {
+                // Two coordinates per field on a single floor, three once floors are involved.
+                let dims = if tokens.len() >= 7 { 3 } else { 2 };
+                let coords = parse_coords(line, &tokens[1..1 + 2 * dims])?;
+                let doors = parse_doors(line, tokens.get(1 + 2 * dims))?;
+                let (rf1, rf2) = if dims == 3 {
+                    (get_field(line, maze, coords[0], coords[1], coords[2])?, get_field(line, maze, coords[3], coords[4], coords[5])?)
+                } else {
+                    (get_field(line, maze, coords[0], coords[1], 0)?, get_field(line, maze, coords[2], coords[3], 0)?)
+                };
+                // A field has room for a single portal, so a second one would silently replace the first.
+                if [&rf1, &rf2].iter().any(|f| f.lock().unwrap().get_transition(Direction::PORTAL).is_some()) {
+                    return Err(format!("Field already has a portal: {}", line));
+                }
+                Transition::new(doors, &Direction::PORTAL, Arc::clone(&rf1), Arc::clone(&rf2));
+                Transition::new(doors, &Direction::PORTAL, rf2, rf1);
+            },
+            ("S", 4..=5) => {
+                let coords = parse_coords(line, &tokens[1..4])?;
+                let doors = parse_doors(line, tokens.get(4))?;
+                let rf1 = get_field(line, maze, coords[0], coords[1], coords[2])?;
+                let rf2 = match coords[2].checked_add(1).and_then(|z| maze.get((coords[0], coords[1], z))) {
+                    Some(f) => f,
+                    None => return Err(format!("No floor above: {}", line)),
+                };
+                Transition::new(doors, &Direction::UP, Arc::clone(&rf1), Arc::clone(&rf2));
+                Transition::new(doors, &Direction::DOWN, rf2, rf1);
+            },
+            ("T", 5..) => schedule(line, &tokens, maze)?,
+            ("H", 4..) => hazard(line, &tokens, maze)?,
+            _ => return Err(format!("Invalid line: {}", line)),
+        }
+    }
+    Ok(())
+}
+
+// Reads the position at the start of an extension line, on the first floor if it has only two
+// coordinates, returning it with the index of the word after it.
+fn parse_position(line: &str, tokens: &[&str]) -> Result<(Position, usize), String> {
+    let invalid = || format!("Invalid line: {}", line);
+    let at = 1 + tokens[1..].iter().position(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())).ok_or_else(invalid)?;
+    match parse_coords(line, &tokens[1..at])?[..] {
+        [x, y] => Ok(((x, y, 0), at)),
+        [x, y, z] => Ok(((x, y, z), at)),
+        _ => Err(invalid()),
+    }
+}
+
+// Reads an `H` line, putting the hazard on the field it names.
+fn hazard(line: &str, tokens: &[&str], maze: &Maze) -> Result<(), String> {
+    let (p, at) = parse_position(line, tokens)?;
+    let count = || tokens.get(at + 1).and_then(|t| t.parse::<u16>().ok());
+    let hazard = match (tokens[at], tokens.len() - at) {
+        ("TRAP", 2) => count().map(Hazard::TRAP),
+        ("SNARE", 2) => count().map(Hazard::SNARE),
+        ("BRIDGE", 1) => Some(Hazard::BRIDGE),
+        ("FORBIDDEN", 1) => Some(Hazard::FORBIDDEN),
+        _ => None,
+    };
+    let Some(hazard) = hazard else {
+        return Err(format!("Invalid line: {}", line));
+    };
+    get_field(line, maze, p.0, p.1, p.2)?.lock().unwrap().set_hazard(Some(hazard));
+    Ok(())
+}
+
+// Reads a `T` line, putting the schedule on the transition it names and the one leading back.
+fn schedule(line: &str, tokens: &[&str], maze: &Maze) -> Result<(), String> {
+    let invalid = || format!("Invalid line: {}", line);
+    let (p, at) = parse_position(line, tokens)?;
+    let direction = *DIRECTIONS.iter().find(|d| format!("{:?}", d) == tokens[at]).ok_or_else(invalid)?;
+    let period = tokens.get(at + 1).and_then(|t| t.parse::<usize>().ok()).ok_or_else(invalid)?;
+    let mut open = Vec::new();
+    for t in &tokens[at + 2..] {
+        let interval = t.split_once('-').and_then(|(from, to)| Some((from.parse::<usize>().ok()?, to.parse::<usize>().ok()?)));
+        match interval {
+            Some((from, to)) if from < to => open.push((from, to)),
+            _ => return Err(invalid()),
+        }
+    }
+    if open.is_empty() {
+        return Err(invalid());
+    }
+    let schedule = Schedule { period, open };
+    let f1 = get_field(line, maze, p.0, p.1, p.2)?;
+    let Some(t) = f1.lock().unwrap().get_transition(direction) else {
+        return Err(format!("No way {:?}: {}", direction, line));
+    };
+    let f2 = t.lock().unwrap().get_field2();
+    let back = f2.lock().unwrap().get_transition(direction.get_opposite())
+        .filter(|b| b.lock().unwrap().get_field2().lock().unwrap().position() == p);
+    if t.lock().unwrap().has_doors() || back.as_ref().is_some_and(|b| b.lock().unwrap().has_doors()) {
+        return Err(format!("Doors that take a key can't keep to a schedule: {}", line));
+    }
+    t.lock().unwrap().set_schedule(Some(schedule.clone()));
+    if let Some(back) = back {
+        back.lock().unwrap().set_schedule(Some(schedule));
+    }
+    Ok(())
+}
+
+/// Builds a maze from the contents of a maze file, described by `FORMAT`.
+pub fn read_maze(content: &str) -> Result<Maze, String> {
+    let mut lines: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+    let (grid, width, height) = match lines.first() {
+        Some(line) => match read_header(line)? {
+            Some(header) => {
+                lines.remove(0);
+                header
+            },
+            None => (Grid::SQUARE, MAZE_X, MAZE_Y),
+        },
+        None => return Err(String::from("Empty maze.")),
+    };
+    let topology = grid.topology();
+    let sides = topology.directions().len();
+
+    let n = usize::from(width) * usize::from(height);
+    let cells = lines.iter().take_while(|l| l.starts_with(|c: char| c.is_ascii_digit())).count();
+    let (cells, extension) = lines.split_at(cells);
+    if cells.is_empty() || !cells.len().is_multiple_of(n) || cells.len() / n > usize::from(u8::MAX) {
+        return Err(format!("Expected floors of {} fields, got {} fields.", n, cells.len()));
+    }
+
+    let mut fields: Vec<Field> = Vec::new();
+    for (i, line) in cells.iter().enumerate() {
+        let (x, y, z) = ((i % usize::from(width)) as u8, ((i / usize::from(width)) % usize::from(height)) as u8, (i / n) as u8);
+        let (_, _, flags) = split_field(line, sides)?;
+        let key = flags[0] == b'1' && flags[1] == b'1';
+        let end = flags[2] == b'1' && flags[3] == b'1';
+        fields.push(Arc::new(Mutex::new(SimpleField::new_3d(x, y, z, key, end))));
+    }
+    let maze = Maze::new(grid, width, height, fields);
+
+    for (i, line) in cells.iter().enumerate() {
+        let (open, doors, _) = split_field(line, sides)?;
+        let rf1 = &maze.fields()[i];
+        let (x, y, z) = rf1.lock().unwrap().position();
+        for (j, d) in topology.directions().iter().enumerate() {
+            if open[j] != b'1' {
+                continue;
+            }
+            if let Some(rf2) = topology.step(x, y, *d).and_then(|(x2, y2)| maze.get((x2, y2, z))) {
+                // println!("Tying {:?}: {} -> {} {}", d, rf1.lock().unwrap(), rf2.lock().unwrap(), doors[j]);
+                Transition::new(doors[j] == b'1', d, Arc::clone(rf1), rf2);
+            }
+        }
+    }
+    tie_extension(extension, &maze)?;
+    Ok(maze)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{maze::has_path, topology::Grid};
+
+    use super::read_maze;
+
+    #[test]
+    fn hex() {
+        // Row 1 is shifted right, so (0,0) reaches (1,1) only through (0,1).
+        let content = "HEX 2 2\n010001 000000 0000\n000000 000000 0000\n010000 000000 0000\n000000 000000 0011\n";
+        let maze = read_maze(content).unwrap();
+        assert_eq!(maze.grid(), Grid::HEX);
+        assert_eq!(maze.size(), (2, 2, 1));
+        assert_eq!(maze.ends().len(), 1);
+
+        let p = has_path(maze.start(), Arc::clone(&maze.ends()[0]));
+        assert_eq!(p.unwrap().to_string(), "SOUTHEAST -> EAST");
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(read_maze("").is_err());
+        assert!(read_maze("SQUARE 1 1\n0000 0000 0000\nX 1 2\n").is_err());
+        assert!(read_maze("SQUARE 1 1\n0000 0000 0000\nS 0 0 0\n").is_err());
+        assert!(read_maze("SQUARE 2 1\n0000 0000 0000\n").is_err());
+        assert!(read_maze("SQUARE 1 1\n0000 0000 0000\nH 0 0 TRAP\n").is_err());
+        assert!(read_maze("SQUARE 1 1\n0000 0000 0000\nH 0 0 PIT\n").is_err());
+        assert!(read_maze("SQUARE 1 1\n0000 0000 0000\nH 1 0 BRIDGE\n").is_err());
+        assert!(read_maze("SQUARE 1 1\n0000 0000 0000\nH 0 0 SNARE 2\n").is_ok());
+        assert!(read_maze("SQUARE 3 1\n0000 0000 0000\n0000 0000 0000\n0000 0000 0000\nP 0 0 2 0\nP 1 0 2 0\n").is_err());
+        // Doors on portals and stairs are either 0 or 1, never a free passage by mistake.
+        let two = "SQUARE 2 1\n0000 0000 0000\n0000 0000 0000\n";
+        assert!(read_maze(&format!("{}P 0 0 1 0 1\n", two)).is_ok());
+        assert!(read_maze(&format!("{}P 0 0 1 0 2\n", two)).is_err());
+        assert!(read_maze(&format!("{}P 0 0 1 0 yes\n", two)).is_err());
+        let floors = "SQUARE 1 1\n0000 0000 0000\n0000 0000 0000\n";
+        assert!(read_maze(&format!("{}S 0 0 0 0\n", floors)).is_ok());
+        assert!(read_maze(&format!("{}S 0 0 0 yes\n", floors)).is_err());
+    }
+}
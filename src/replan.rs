@@ -0,0 +1,386 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}, sync::Arc};
+use crate::{maze::{refuse_hazards, safe_neighbours, safe_position, Direction, Hazard, Maze, Path, Position}, topology::Grid};
+
+// Field, keys picked up and keys in hand, as in `graph::KeyStates`.
+type State = (usize, u64, u16);
+
+// Every state on an exit leads on to this one for free, so all exits are a single goal.
+const GOAL: State = (usize::MAX, 0, 0);
+const INFINITY: usize = usize::MAX;
+
+/// Shortest way out of a maze, kept up to date as the maze changes, using Lifelong Planning A*.
+///
+/// Searches the same states as `paths`: a field together with the keys picked up and the keys in
+/// hand. Changes made through the maze's editing methods are picked up on the next call to `path`,
+/// and only the states whose moves they touch are looked at again before the search carries on
+/// from where it was. Snares take keys in hand as they do there, but traps and bridges aren't kept
+/// to, so the planner returns an error for a maze with any of them.
+pub struct Planner {
+    revision: usize,
+    grid: Grid,
+    size: (u8, u8, u8),
+    positions: Vec<Position>,
+    exits: Vec<Position>,
+    // Portals and transitions to fields that aren't neighbours can jump across the maze, so distances
+    // on the grid only estimate the cost without them.
+    heuristic: bool,
+    // Bit of every key field in the masks of keys picked up, kept for as long as the planner lives.
+    bits: HashMap<usize, u32>,
+    start: State,
+    g: HashMap<State, usize>,
+    rhs: HashMap<State, usize>,
+    // Moves out of every state expanded so far, and the states known to lead to every state.
+    moves: HashMap<State, Vec<(Option<Direction>, State)>>,
+    preds: HashMap<State, HashSet<State>>,
+    queue: BinaryHeap<Reverse<((usize, usize), State)>>,
+    expanded: usize,
+}
+
+impl Planner {
+    /// Returns an error if the maze has more than 64 keys, as the keys picked up are tracked in a
+    /// bitmask, or if it has traps or bridges.
+    pub fn new(maze: &Maze) -> Result<Self, String> {
+        check_hazards(maze)?;
+        let positions: Vec<Position> = maze.fields().iter().map(|f| safe_position(Arc::clone(f))).collect();
+        let mut p = Planner {
+            revision: maze.revision(),
+            grid: maze.grid(),
+            size: maze.size(),
+            positions,
+            exits: Vec::new(),
+            heuristic: true,
+            bits: HashMap::new(),
+            start: (0, 0, 0),
+            g: HashMap::new(),
+            rhs: HashMap::new(),
+            moves: HashMap::new(),
+            preds: HashMap::new(),
+            queue: BinaryHeap::new(),
+            expanded: 0,
+        };
+        p.exits = p.exits(maze);
+        p.heuristic = !(0..p.positions.len()).any(|i| p.has_jump(maze, i));
+        p.check_keys(maze)?;
+        p.start = p.arrive(maze, 0, 0, 0);
+        p.rhs.insert(p.start, 0);
+        p.queue.push(Reverse((p.key(p.start), p.start)));
+        Ok(p)
+    }
+
+    // Every field that has ever had a key keeps its bit, so those and the keys lying in the maze now
+    // have to fit into the mask together.
+    fn check_keys(&self, maze: &Maze) -> Result<(), String> {
+        let new = (0..self.positions.len())
+            .filter(|i| !self.bits.contains_key(i) && maze.fields()[*i].lock().unwrap().has_key())
+            .count();
+        match self.bits.len() + new {
+            n if n > 64 => Err(format!("At most 64 keys can be tracked, the maze has had {}.", n)),
+            _ => Ok(()),
+        }
+    }
+
+    fn index(&self, p: Position) -> usize {
+        let (width, height, _) = self.size;
+        (usize::from(p.2) * usize::from(height) + usize::from(p.1)) * usize::from(width) + usize::from(p.0)
+    }
+
+    fn exits(&self, maze: &Maze) -> Vec<Position> {
+        maze.ends().iter().map(|f| safe_position(Arc::clone(f))).collect()
+    }
+
+    fn has_jump(&self, maze: &Maze, i: usize) -> bool {
+        let topology = self.grid.topology();
+        safe_neighbours(Arc::clone(&maze.fields()[i]))
+            .into_iter()
+            .any(|(d, _, f)| !topology.is_step(self.positions[i], d, safe_position(f)))
+    }
+
+    // State after stepping on field `i`, losing keys in hand to its snare and then picking up its
+    // key if it wasn't picked up before.
+    fn arrive(&mut self, maze: &Maze, i: usize, keys: u64, hand: u16) -> State {
+        let field = maze.fields()[i].lock().unwrap();
+        let (key, hazard) = (field.has_key(), field.get_hazard());
+        drop(field);
+        let hand = match hazard {
+            Some(Hazard::SNARE(n)) => hand.saturating_sub(n),
+            _ => hand,
+        };
+        if !key {
+            return (i, keys, hand);
+        }
+        let next = self.bits.len() as u32;
+        let bit = *self.bits.entry(i).or_insert(next);
+        if keys & (1 << bit) == 0 {
+            (i, keys | 1 << bit, hand + 1)
+        } else {
+            (i, keys, hand)
+        }
+    }
+
+    fn successors(&mut self, maze: &Maze, u: State) -> Vec<(Option<Direction>, State)> {
+        if u == GOAL {
+            return Vec::new();
+        }
+        let (i, keys, hand) = u;
+        let f = Arc::clone(&maze.fields()[i]);
+        let mut result = Vec::new();
+        if f.lock().unwrap().is_end() {
+            result.push((None, GOAL));
+        }
+        for (d, doors, f2) in safe_neighbours(f) {
+            if doors && hand == 0 {
+                continue;
+            }
+            let j = self.index(safe_position(f2));
+            let v = self.arrive(maze, j, keys, if doors { hand - 1 } else { hand });
+            result.push((Some(d), v));
+        }
+        result
+    }
+
+    fn g(&self, s: State) -> usize {
+        self.g.get(&s).copied().unwrap_or(INFINITY)
+    }
+
+    fn rhs(&self, s: State) -> usize {
+        self.rhs.get(&s).copied().unwrap_or(INFINITY)
+    }
+
+    fn estimate(&self, s: State) -> usize {
+        if !self.heuristic || s == GOAL {
+            return 0;
+        }
+        let topology = self.grid.topology();
+        self.exits.iter().map(|e| usize::from(topology.distance(self.positions[s.0], *e))).min().unwrap_or(0)
+    }
+
+    fn key(&self, s: State) -> (usize, usize) {
+        let m = self.g(s).min(self.rhs(s));
+        (m.saturating_add(self.estimate(s)), m)
+    }
+
+    // Cost of the cheapest known way into `v`, queueing it if that differs from the cost it was expanded with.
+    fn update(&mut self, v: State) {
+        if v != self.start {
+            let best = self.preds.get(&v).into_iter().flatten()
+                .filter_map(|p| self.moves.get(p)?.iter().filter(|(_, w)| *w == v).map(|(d, _)| self.g(*p).saturating_add(usize::from(d.is_some()))).min())
+                .min()
+                .unwrap_or(INFINITY);
+            self.rhs.insert(v, best);
+        }
+        if self.g(v) != self.rhs(v) {
+            self.queue.push(Reverse((self.key(v), v)));
+        }
+    }
+
+    fn moves_of(&mut self, maze: &Maze, u: State) -> Vec<(Option<Direction>, State)> {
+        if let Some(m) = self.moves.get(&u) {
+            return m.clone();
+        }
+        let m = self.successors(maze, u);
+        m.iter().for_each(|(_, v)| { self.preds.entry(*v).or_default().insert(u); });
+        self.moves.insert(u, m.clone());
+        m
+    }
+
+    // Looks again at the moves of every expanded state on, or leading to, a field changed since the last call.
+    fn catch_up(&mut self, maze: &Maze) -> Result<(), String> {
+        let changed: HashSet<usize> = maze.changes_since(self.revision).iter().map(|p| self.index(*p)).collect();
+        self.revision = maze.revision();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        if changed.contains(&0) && self.arrive(maze, 0, 0, 0) != self.start {
+            // A key appeared or disappeared under the start, which changes where every path begins.
+            *self = Planner::new(maze)?;
+            return Ok(());
+        }
+        let exits = self.exits(maze);
+        let heuristic = self.heuristic && !changed.iter().any(|i| self.has_jump(maze, *i));
+        let rekey = exits != self.exits || heuristic != self.heuristic;
+        self.exits = exits;
+        self.heuristic = heuristic;
+
+        let affected: Vec<State> = self.moves.iter()
+            .filter(|(u, m)| changed.contains(&u.0) || m.iter().any(|(_, v)| *v != GOAL && changed.contains(&v.0)))
+            .map(|(u, _)| *u)
+            .collect();
+        for u in affected {
+            let old = self.moves.remove(&u).unwrap_or_default();
+            let new = self.moves_of(maze, u);
+            let touched: HashSet<State> = old.iter().chain(new.iter()).map(|(_, v)| *v).collect();
+            touched.into_iter().for_each(|v| self.update(v));
+        }
+        if rekey {
+            let inconsistent: Vec<State> = self.rhs.keys().chain(self.g.keys()).copied().filter(|s| self.g(*s) != self.rhs(*s)).collect();
+            self.queue = inconsistent.into_iter().map(|s| Reverse((self.key(s), s))).collect();
+        }
+        Ok(())
+    }
+
+    fn compute(&mut self, maze: &Maze) {
+        self.expanded = 0;
+        while let Some(Reverse((k, u))) = self.queue.peek().copied() {
+            // Ties with the goal are settled too, so that every state on a shortest path is consistent.
+            if k > self.key(GOAL) && self.g(GOAL) == self.rhs(GOAL) {
+                break;
+            }
+            self.queue.pop();
+            let (gu, ru) = (self.g(u), self.rhs(u));
+            // Left behind when the state was queued again with another key, or became consistent.
+            if gu == ru || k != self.key(u) {
+                continue;
+            }
+            self.expanded += 1;
+            if gu > ru {
+                self.g.insert(u, ru);
+            } else {
+                self.g.insert(u, INFINITY);
+                self.update(u);
+            }
+            for (_, v) in self.moves_of(maze, u) {
+                self.update(v);
+            }
+        }
+    }
+
+    /// Shortest path from the start of the maze to its nearest exit, repairing the previous search
+    /// after changes to the maze. Returns an error if more than 64 fields have had keys since the
+    /// planner was made, or if the maze has traps or bridges now.
+    pub fn path(&mut self, maze: &Maze) -> Result<Option<Path>, String> {
+        check_hazards(maze)?;
+        self.check_keys(maze)?;
+        self.catch_up(maze)?;
+        self.compute(maze);
+        if self.g(GOAL) == INFINITY {
+            return Ok(None);
+        }
+        let mut steps = Vec::new();
+        let mut v = GOAL;
+        while v != self.start {
+            let gv = self.g(v);
+            let Some((d, p)) = self.preds.get(&v).and_then(|preds| preds.iter()
+                .filter_map(|p| self.moves.get(p)?.iter().find(|(_, w)| *w == v).map(|(d, _)| (*d, *p)))
+                .find(|(d, p)| self.g(*p).saturating_add(usize::from(d.is_some())) == gv)) else {
+                return Ok(None);
+            };
+            steps.extend(d);
+            v = p;
+        }
+        steps.reverse();
+        Ok(Some(Path::from_steps(steps)))
+    }
+
+    /// Number of states the last call to `path` expanded.
+    pub fn expanded(&self) -> usize {
+        self.expanded
+    }
+}
+
+fn check_hazards(maze: &Maze) -> Result<(), String> {
+    refuse_hazards(maze.fields(), "The planner", |h| matches!(h, Hazard::TRAP(_) | Hazard::BRIDGE))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixtures::open_grid, maze::{Direction, Hazard, Maze}, paths::k_min_paths, reader::read_maze};
+
+    use super::Planner;
+
+    fn fresh(maze: &Maze) -> Option<usize> {
+        k_min_paths(maze.start(), maze.ends(), 1).unwrap().first().map(|p| p.cost())
+    }
+
+    #[test]
+    fn follows_changes() {
+        // (0,0) k --- (1,0) -|- (2,0) --- (3,0) end
+        //   |                               |
+        // (0,1) ----- (1,1) ----- (2,1) --- (3,1)
+        let mut maze = read_maze("SQUARE 4 2\n0101 0000 1100\n1100 0100 0000\n1100 1000 0000\n1001 0000 0011\n0110 0000 0000\n1100 0000 0000\n1100 0000 0000\n1010 0000 0000\n").unwrap();
+        let mut planner = Planner::new(&maze).unwrap();
+        assert_eq!(planner.path(&maze).unwrap().unwrap().to_string(), "EAST -> EAST -> EAST");
+
+        // Without the key the doors stay shut and the way round is the only one.
+        assert!(maze.set_key((0, 0, 0), false));
+        assert_eq!(planner.path(&maze).unwrap().map(|p| p.cost()), Some(5));
+        assert!(maze.set_key((1, 1, 0), true));
+        assert_eq!(planner.path(&maze).unwrap().map(|p| p.cost()), fresh(&maze));
+
+        // Walls going up, and doors being taken away.
+        assert!(maze.remove_transition((3, 1, 0), Direction::NORTH));
+        assert!(!maze.remove_transition((3, 1, 0), Direction::NORTH));
+        assert_eq!(planner.path(&maze).unwrap().map(|p| p.cost()), fresh(&maze));
+        assert!(maze.toggle_door((1, 0, 0), Direction::EAST));
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 3);
+        assert!(maze.remove_transition((2, 0, 0), Direction::EAST));
+        assert!(planner.path(&maze).unwrap().is_none());
+        assert!(maze.add_transition((2, 1, 0), Direction::NORTH, (2, 0, 0), false));
+        assert!(maze.add_transition((2, 0, 0), Direction::EAST, (3, 0, 0), true));
+        assert_eq!(planner.path(&maze).unwrap().map(|p| p.cost()), fresh(&maze));
+        assert_eq!(maze.changes_since(0).len(), 10);
+    }
+
+    #[test]
+    fn jumps() {
+        // Transitions leading far away from where a step would land, like portals.
+        let mut maze = read_maze(&open_grid(10, 10, (9, 0), &[])).unwrap();
+        let mut planner = Planner::new(&maze).unwrap();
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 9);
+        assert!(maze.add_transition((0, 0, 0), Direction::SOUTH, (9, 9, 0), false));
+        assert!(maze.add_transition((9, 9, 0), Direction::NORTH, (9, 0, 0), false));
+        assert_eq!(planner.path(&maze).unwrap().map(|p| p.cost()), Some(2));
+        assert_eq!(fresh(&maze), Some(2));
+        assert_eq!(Planner::new(&maze).unwrap().path(&maze).unwrap().map(|p| p.cost()), Some(2));
+    }
+
+    #[test]
+    fn repairs_locally() {
+        let mut maze = read_maze(&open_grid(12, 12, (11, 0), &[])).unwrap();
+        let mut planner = Planner::new(&maze).unwrap();
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 11);
+        let from_scratch = planner.expanded();
+
+        // A wall far away from the way out leaves the path alone and takes hardly any work.
+        assert!(maze.remove_transition((5, 10, 0), Direction::SOUTH));
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 11);
+        assert!(planner.expanded() < from_scratch);
+
+        // Walling off the first step forces a detour.
+        assert!(maze.remove_transition((0, 0, 0), Direction::EAST));
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 13);
+        assert_eq!(planner.path(&maze).unwrap().map(|p| p.cost()), fresh(&maze));
+    }
+
+    #[test]
+    fn too_many_keys() {
+        // 64 keys in a row fit, and a key on the exit is one too many.
+        let mut content = String::from("SQUARE 65 1\n");
+        for x in 0..65 {
+            let open = [x > 0, x < 64].map(|o| if o { '1' } else { '0' });
+            let flags = if x < 64 { "1100" } else { "0011" };
+            content.push_str(&format!("{}00 0000 {}\n", open.iter().collect::<String>(), flags));
+        }
+        let mut maze = read_maze(&content).unwrap();
+        let mut planner = Planner::new(&maze).unwrap();
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 64);
+        assert!(maze.set_key((64, 0, 0), true));
+        assert!(planner.path(&maze).is_err());
+        assert!(Planner::new(&maze).is_err());
+    }
+
+    #[test]
+    fn hazards() {
+        // (0,0) --- (1,0) --- (2,0) k
+        //   |
+        //  -|- (0,1) end
+        let mut maze = read_maze("SQUARE 3 2\n0101 0001 0000\n1100 0000 0000\n1000 0000 1100\n0010 0010 0011\n0000 0000 0000\n0000 0000 0000\n").unwrap();
+        let mut planner = Planner::new(&maze).unwrap();
+        assert_eq!(planner.path(&maze).unwrap().unwrap().cost(), 5);
+        // The key is lost on the way back.
+        assert!(maze.set_hazard((1, 0, 0), Some(Hazard::SNARE(1))));
+        assert!(planner.path(&maze).unwrap().is_none());
+        assert!(maze.set_hazard((1, 0, 0), Some(Hazard::TRAP(2))));
+        assert!(planner.path(&maze).is_err());
+        assert!(Planner::new(&maze).is_err());
+    }
+}
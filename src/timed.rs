@@ -0,0 +1,257 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}, sync::Arc};
+use crate::maze::{safe_position, Direction, Field, Hazard, Maze, Path, Position, Rules, Schedule, SimpleField, DIRECTIONS};
+
+// Field, keys picked up, keys in hand, doors left open, bridges crossed, tick, and the state and move
+// it was reached from.
+type State = (usize, u64, u16, u64, u64, usize, Option<(usize, Direction)>);
+
+// A way out of a field: its direction, whether it has doors, when it is open and the field it leads to.
+type Move = (Direction, bool, Option<Schedule>, usize);
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// Folds ticks onto the ones that behave the same: every tick before the last one-off interval
+// ends stands for itself, after it the schedules only repeat.
+fn clock(moves: &[Vec<Move>]) -> impl Fn(usize) -> usize {
+    let schedules = moves.iter().flatten().filter_map(|(_, _, s, _)| s.as_ref());
+    let (mut settled, mut period) = (0, 1);
+    for s in schedules {
+        if s.period == 0 {
+            settled = s.open.iter().map(|(_, to)| *to).fold(settled, usize::max);
+        } else {
+            period = period / gcd(period, s.period) * s.period;
+        }
+    }
+    move |t| if t < settled { t } else { settled + (t - settled) % period }
+}
+
+// Gives every one of `marked` a bit of its own, as long as there are bits enough.
+fn bits<T: Eq + std::hash::Hash>(marked: Vec<T>, what: &str) -> Result<HashMap<T, u32>, String> {
+    if marked.len() > 64 {
+        return Err(format!("At most 64 {} can be tracked, the maze has {}.", what, marked.len()));
+    }
+    Ok(marked.into_iter().zip(0..).collect())
+}
+
+// The fields for which `has` holds.
+fn marked(fields: &[Field], has: impl Fn(&SimpleField) -> bool) -> Vec<usize> {
+    (0..fields.len()).filter(|i| has(&fields[*i].lock().unwrap())).collect()
+}
+
+/// Shortest way out of the maze from its first field when transitions keep to their schedules,
+/// one tick per move. A move can start only at a tick its transition is open, so the path may
+/// wait in place, with a `Direction::WAIT` step for every tick waited, and its cost is the tick
+/// it gets out on. Keys and doors work as in the key-aware search: a key is picked up the first
+/// time its field is stepped on and every pass through doors takes one.
+///
+/// Hazards on the fields are kept to as well: the ticks a trap holds the path show as `WAIT`
+/// steps after the move onto it, snares take keys, bridges are stepped on once and forbidden
+/// fields never. Of the `rules`, unlocked doors staying open and the number of keys that fit in
+/// hand are kept to; the others only choose between solvers that don't know about time.
+///
+/// Returns an error if there are more than 64 keys, bridges or, with doors staying open, doors,
+/// as each of them is tracked in a bitmask.
+pub fn min_path_timed(maze: &Maze, rules: Rules) -> Result<Option<Path>, String> {
+    let fields = maze.fields();
+    let index: HashMap<Position, usize> = fields.iter().enumerate().map(|(i, f)| (safe_position(Arc::clone(f)), i)).collect();
+    let hazards: Vec<Option<Hazard>> = fields.iter().map(|f| f.lock().unwrap().get_hazard()).collect();
+    let moves: Vec<Vec<Move>> = fields.iter().map(|f| {
+        let lf = f.lock().unwrap();
+        DIRECTIONS.iter().filter_map(|d| {
+            let t = lf.get_transition(*d)?;
+            let lt = t.lock().unwrap();
+            let to = *index.get(&safe_position(lt.get_field2()))?;
+            (hazards[to] != Some(Hazard::FORBIDDEN)).then(|| (*d, lt.has_doors(), lt.get_schedule(), to))
+        }).collect()
+    }).collect();
+    let end: Vec<bool> = fields.iter().map(|f| f.lock().unwrap().is_end()).collect();
+    let keys = bits(marked(fields, |f| f.has_key()), "keys")?;
+    let bridges = bits(marked(fields, |f| f.get_hazard() == Some(Hazard::BRIDGE)), "bridges")?;
+    // Doors by the fields they connect, the smaller number first, if they stay open once unlocked.
+    let mut pairs = Vec::new();
+    if rules.doors_stay_open {
+        for (i, m) in moves.iter().enumerate() {
+            for (_, _, _, j) in m.iter().filter(|(_, doors, _, _)| *doors) {
+                if !pairs.contains(&(i.min(*j), i.max(*j))) {
+                    pairs.push((i.min(*j), i.max(*j)));
+                }
+            }
+        }
+    }
+    let door_bits = bits(pairs, "doors")?;
+    // Where stepping on a field leaves the keys picked up and in hand.
+    let pick = |i: usize, picked: u64, hand: u16| {
+        let hand = match hazards[i] {
+            Some(Hazard::SNARE(n)) => hand.saturating_sub(n),
+            _ => hand,
+        };
+        match keys.get(&i) {
+            Some(b) if picked & (1 << b) == 0 && rules.capacity.is_none_or(|c| hand < c) => (picked | 1 << b, hand + 1),
+            _ => (picked, hand),
+        }
+    };
+    let cross = |i: usize, crossed: u64| bridges.get(&i).map_or(crossed, |b| crossed | 1 << b);
+    let held = |i: usize| match hazards[i] {
+        Some(Hazard::TRAP(n)) => usize::from(n),
+        _ => 0,
+    };
+    let clock = clock(&moves);
+
+    // Dijkstra over the ticks, so the first exit taken off the heap is reached as early as possible.
+    let (picked, hand) = pick(0, 0, 0);
+    let mut states: Vec<State> = vec![(0, picked, hand, 0, cross(0, 0), 0, None)];
+    let mut seen = HashSet::new();
+    let mut heap = BinaryHeap::from([Reverse((0, 0))]);
+    while let Some(Reverse((t, s))) = heap.pop() {
+        let (i, picked, hand, opened, crossed, _, _) = states[s];
+        if !seen.insert((i, picked, hand, opened, crossed, clock(t))) {
+            continue;
+        }
+        if end[i] {
+            return Ok(Some(unwind(&states, s, held)));
+        }
+        // Moving on before waiting, so of the paths that get out as early, the one waiting last is found.
+        let wait = std::iter::once((Direction::WAIT, false, None, i));
+        for (d, doors, schedule, j) in moves[i].iter().cloned().chain(wait) {
+            if schedule.is_some_and(|s| !s.is_open(t)) {
+                continue;
+            }
+            if j != i && bridges.get(&j).is_some_and(|b| crossed & (1 << b) != 0) {
+                continue;
+            }
+            let (mut hand, mut opened) = (hand, opened);
+            if doors {
+                let door = door_bits.get(&(i.min(j), i.max(j)));
+                if door.is_none_or(|b| opened & (1 << b) == 0) {
+                    if hand == 0 {
+                        continue;
+                    }
+                    hand -= 1;
+                    opened |= door.map_or(0, |b| 1 << b);
+                }
+            }
+            let (picked, hand) = if j == i { (picked, hand) } else { pick(j, picked, hand) };
+            let next = t + 1 + if j == i { 0 } else { held(j) };
+            states.push((j, picked, hand, opened, cross(j, crossed), next, Some((s, d))));
+            heap.push(Reverse((next, states.len() - 1)));
+        }
+    }
+    Ok(None)
+}
+
+// The moves that led to the state `last`, with the ticks spent in traps as waits.
+fn unwind(states: &[State], last: usize, held: impl Fn(usize) -> usize) -> Path {
+    let mut steps = Vec::new();
+    let mut curr = last;
+    while let Some((prev, d)) = states[curr].6 {
+        if d != Direction::WAIT {
+            steps.extend(std::iter::repeat_n(Direction::WAIT, held(states[curr].0)));
+        }
+        steps.push(d);
+        curr = prev;
+    }
+    steps.reverse();
+    Path::from_steps(steps)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixtures::open_grid, maze::{min_path, Direction, Hazard, Mode, Rules, Schedule}, reader::read_maze};
+
+    use super::min_path_timed;
+
+    // (0,0) --- (1,0) --- (2,0) end
+    const CORRIDOR: &str = "SQUARE 3 1\n0100 0000 0000\n1100 0000 0000\n1000 0000 0011\n";
+
+    #[test]
+    fn wait_for_the_doors() {
+        // The way into the exit opens on even ticks only, and the field before it is reached on tick 1.
+        let content = format!("{}T 1 0 EAST 2 0-1\n", CORRIDOR);
+        let mut maze = read_maze(&content).unwrap();
+        let p = min_path_timed(&maze, Rules::default()).unwrap().unwrap();
+        assert_eq!(p.to_string(), "EAST -> WAIT -> EAST");
+        assert_eq!(p.cost(), 3);
+        // The other solvers don't know about time.
+        assert_eq!(min_path(maze.start(), maze.ends(), Mode::SERIAL).unwrap().cost(), 2);
+
+        // Waiting at the start does just as well, but not better.
+        assert!(maze.set_schedule((0, 0, 0), Direction::EAST, Some(Schedule::even())));
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().cost(), 3);
+        assert!(maze.set_schedule((1, 0, 0), Direction::EAST, None));
+        assert!(!maze.set_schedule((1, 0, 0), Direction::EAST, None));
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().to_string(), "EAST -> EAST");
+        // The schedule went on the way back too.
+        let back = maze.get((1, 0, 0)).unwrap().lock().unwrap().get_transition(Direction::WEST).unwrap();
+        assert_eq!(back.lock().unwrap().get_schedule(), Some(Schedule::even()));
+        // Doors that take a key replace the ones on a schedule.
+        assert!(maze.set_door((0, 0, 0), Direction::EAST, true));
+        assert_eq!(back.lock().unwrap().get_schedule(), None);
+        assert!(back.lock().unwrap().has_doors());
+        assert!(!maze.add_transition((0, 0, 0), Direction::WAIT, (1, 0, 0), false));
+    }
+
+    #[test]
+    fn too_late() {
+        // Open from tick 3 to 5 only: in time by waiting, but not once it has shut for good.
+        let maze = read_maze(&format!("{}T 1 0 EAST 0 3-5\n", CORRIDOR)).unwrap();
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().cost(), 4);
+        let maze = read_maze(&format!("{}T 0 0 EAST 0 0-1\nT 1 0 EAST 0 0-1\n", CORRIDOR)).unwrap();
+        assert!(min_path_timed(&maze, Rules::default()).unwrap().is_none());
+        // Every third tick from the first and every other from the second: straight through.
+        let maze = read_maze(&format!("{}T 0 0 EAST 3 0-1\nT 1 0 EAST 2 1-2\n", CORRIDOR)).unwrap();
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().to_string(), "EAST -> EAST");
+        assert!(read_maze(&format!("{}T 1 0 EAST 2\n", CORRIDOR)).is_err());
+        assert!(read_maze(&format!("{}T 1 0 UP 2 0-1\n", CORRIDOR)).is_err());
+        let locked = CORRIDOR.replace("1100 0000 0000", "1100 0100 0000");
+        assert!(read_maze(&format!("{}T 1 0 EAST 2 0-1\n", locked)).is_err());
+    }
+
+    #[test]
+    fn hazards() {
+        // A trap on the straight way to the exit, and a detour round it.
+        let mut content = open_grid(3, 2, (2, 0), &[]);
+        content.push_str("H 1 0 TRAP 3\n");
+        let mut maze = read_maze(&content).unwrap();
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().to_string(), "SOUTH -> EAST -> EAST -> NORTH");
+        maze.set_hazard((1, 1, 0), Some(Hazard::FORBIDDEN));
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().to_string(), "EAST -> WAIT -> WAIT -> WAIT -> EAST");
+
+        // The key behind the bridge can be fetched but not brought back over it.
+        let content = "SQUARE 3 2\n0101 0001 0000\n1100 0000 0000\n1000 0000 1100\n0010 0010 0011\n0000 0000 0000\n0000 0000 0000\n";
+        let mut maze = read_maze(content).unwrap();
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().cost(), 5);
+        maze.set_hazard((1, 0, 0), Some(Hazard::TRAP(2)));
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().cost(), 5 + 2 * 2);
+        for hazard in [Hazard::BRIDGE, Hazard::SNARE(1)] {
+            maze.set_hazard((1, 0, 0), Some(hazard));
+            assert!(min_path_timed(&maze, Rules::default()).unwrap().is_none(), "{:?} didn't get in the way", hazard);
+        }
+    }
+
+    #[test]
+    fn rules() {
+        // (0,0) k -|- (1,0) k --- (2,0) k
+        //    :
+        // (0,1) -|- (1,1) end
+        let maze = read_maze("SQUARE 3 2\n0101 0101 1100\n1100 1000 1100\n1000 0000 1100\n0110 0110 0000\n1000 1000 0011\n0000 0000 0000\n").unwrap();
+        assert!(min_path_timed(&maze, Rules::default()).unwrap().is_none());
+        // The keys behind the doors are worth fetching only if the doors stay open on the way back.
+        let rules = Rules { doors_stay_open: true, ..Rules::default() };
+        assert_eq!(min_path_timed(&maze, rules).unwrap().unwrap().to_string(), "EAST -> EAST -> WEST -> WEST -> SOUTH -> EAST");
+
+        // (0,0) k --- (1,0) k -|- (2,0) -|- (3,0) end
+        //                            |          |
+        //                          (2,1) k --- (3,1)
+        let mut content = String::from("SQUARE 4 2\n0100 0000 1100\n1100 0100 1100\n1101 1100 0000\n1001 1001 0011\n");
+        content.push_str("0000 0000 0000\n0000 0000 0000\n0110 0000 1100\n1010 0010 0000\n");
+        let maze = read_maze(&content).unwrap();
+        assert_eq!(min_path_timed(&maze, Rules::default()).unwrap().unwrap().cost(), 3);
+        let rules = Rules { capacity: Some(1), ..Rules::default() };
+        assert_eq!(min_path_timed(&maze, rules).unwrap().unwrap().to_string(), "EAST -> EAST -> SOUTH -> EAST -> NORTH");
+
+        let content = format!("SQUARE 65 1\n{}", "0000 0000 1100\n".repeat(65));
+        assert!(min_path_timed(&read_maze(&content).unwrap(), Rules::default()).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::maze::{Direction, Position};
+
+/// Shape of a maze's cells: which neighbours a cell has and how far apart two cells are.
+pub trait Topology {
+    /// Directions to the neighbouring cells on a floor, in the order they are listed in a maze file.
+    fn directions(&self) -> &'static [Direction];
+
+    /// Column and row one step away in `direction`, or `None` if that falls off the top or left edge.
+    fn step(&self, x: u8, y: u8, direction: Direction) -> Option<(u8, u8)>;
+
+    /// Lower bound on the number of steps between two positions, where changing a floor is one step.
+    fn distance(&self, p1: Position, p2: Position) -> u16;
+
+    /// Whether a transition from `from` in `direction` ends where a step that way lands, so that
+    /// `distance` stays a lower bound across it. Stairs lead straight up or down one floor.
+    fn is_step(&self, from: Position, direction: Direction, to: Position) -> bool {
+        let (x, y, z) = from;
+        match direction {
+            Direction::UP => z.checked_add(1) == Some(to.2) && (x, y) == (to.0, to.1),
+            Direction::DOWN => z.checked_sub(1) == Some(to.2) && (x, y) == (to.0, to.1),
+            d => z == to.2 && self.step(x, y, d) == Some((to.0, to.1)),
+        }
+    }
+}
+
+/// Square cells with four neighbours.
+pub struct SquareGrid;
+
+impl Topology for SquareGrid {
+    fn directions(&self) -> &'static [Direction] {
+        &[Direction::WEST, Direction::EAST, Direction::NORTH, Direction::SOUTH]
+    }
+
+    fn step(&self, x: u8, y: u8, direction: Direction) -> Option<(u8, u8)> {
+        match direction {
+            Direction::WEST => Some((x.checked_sub(1)?, y)),
+            Direction::EAST => Some((x.checked_add(1)?, y)),
+            Direction::NORTH => Some((x, y.checked_sub(1)?)),
+            Direction::SOUTH => Some((x, y.checked_add(1)?)),
+            _ => None,
+        }
+    }
+
+    fn distance(&self, p1: Position, p2: Position) -> u16 {
+        u16::from(p2.0.abs_diff(p1.0)) + u16::from(p2.1.abs_diff(p1.1)) + u16::from(p2.2.abs_diff(p1.2))
+    }
+}
+
+/// Pointy-top hexagons with six neighbours, stored in rows where every odd row is shifted half a cell to the right.
+pub struct HexGrid;
+
+impl HexGrid {
+    // Axial coordinates (q, r) of a cell, in which the hex distance is easy to compute.
+    fn axial(x: u8, y: u8) -> (i16, i16) {
+        let (x, y) = (i16::from(x), i16::from(y));
+        (x - (y - (y & 1)) / 2, y)
+    }
+}
+
+impl Topology for HexGrid {
+    fn directions(&self) -> &'static [Direction] {
+        &[Direction::WEST, Direction::EAST, Direction::NORTHWEST, Direction::NORTHEAST, Direction::SOUTHWEST, Direction::SOUTHEAST]
+    }
+
+    fn step(&self, x: u8, y: u8, direction: Direction) -> Option<(u8, u8)> {
+        // On odd rows the diagonal neighbours are one column further right.
+        let odd = y % 2 == 1;
+        match direction {
+            Direction::WEST => Some((x.checked_sub(1)?, y)),
+            Direction::EAST => Some((x.checked_add(1)?, y)),
+            Direction::NORTHWEST if odd => Some((x, y.checked_sub(1)?)),
+            Direction::NORTHWEST => Some((x.checked_sub(1)?, y.checked_sub(1)?)),
+            Direction::NORTHEAST if odd => Some((x.checked_add(1)?, y.checked_sub(1)?)),
+            Direction::NORTHEAST => Some((x, y.checked_sub(1)?)),
+            Direction::SOUTHWEST if odd => Some((x, y.checked_add(1)?)),
+            Direction::SOUTHWEST => Some((x.checked_sub(1)?, y.checked_add(1)?)),
+            Direction::SOUTHEAST if odd => Some((x.checked_add(1)?, y.checked_add(1)?)),
+            Direction::SOUTHEAST => Some((x, y.checked_add(1)?)),
+            _ => None,
+        }
+    }
+
+    fn distance(&self, p1: Position, p2: Position) -> u16 {
+        let (q1, r1) = HexGrid::axial(p1.0, p1.1);
+        let (q2, r2) = HexGrid::axial(p2.0, p2.1);
+        let (dq, dr) = (q2 - q1, r2 - r1);
+        let flat = (dq.abs() + dr.abs() + (dq + dr).abs()) / 2;
+        flat as u16 + u16::from(p2.2.abs_diff(p1.2))
+    }
+}
+
+/// The topologies a maze can be built on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Grid {
+    #[default]
+    SQUARE,
+    HEX,
+}
+
+impl Grid {
+    pub fn topology(&self) -> &'static dyn Topology {
+        match self {
+            Grid::SQUARE => &SquareGrid,
+            Grid::HEX => &HexGrid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Grid;
+    use crate::maze::Direction;
+
+    #[test]
+    fn hex_neighbours() {
+        let hex = Grid::HEX.topology();
+        for d in hex.directions() {
+            // Every neighbour is one step away and stepping back returns to the start.
+            for (x, y) in [(2, 2), (2, 3)] {
+                let (nx, ny) = hex.step(x, y, *d).unwrap();
+                assert_eq!(hex.distance((x, y, 0), (nx, ny, 0)), 1);
+                assert_eq!(hex.step(nx, ny, d.get_opposite()), Some((x, y)));
+            }
+        }
+        assert_eq!(hex.distance((0, 0, 0), (3, 3, 0)), 5);
+        assert_eq!(hex.step(0, 0, Direction::NORTH), None);
+    }
+
+    #[test]
+    fn far_corners() {
+        // Distances across a large maze don't fit into a byte on either grid.
+        assert_eq!(Grid::SQUARE.topology().distance((0, 0, 0), (255, 255, 255)), 765);
+        assert_eq!(Grid::HEX.topology().distance((0, 0, 0), (255, 255, 255)), 638);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
+use crate::{graph::Graph, maze::{Direction, Field, Hazard, Maze, Path, Rules}};
+
+// Field, marked fields, keys still in hand and doors left open.
+type State = (usize, u64, u16, u64);
+
+struct Tour {
+    graph: Graph,
+    rules: Rules,
+    // Bit of every field with a key or required to be visited. The bit of a field with a key is set
+    // once the key is picked up, that of any other field once it is stepped on.
+    marks: Vec<Option<u32>>,
+    all_required: u64,
+    // Bit of every pair of fields with doors between them, when unlocked doors stay open.
+    doors: HashMap<(usize, usize), u32>,
+    // Only the given ends count as exits.
+    ends: Vec<bool>,
+}
+
+impl Tour {
+    // State after stepping onto field `i`, picking up its key if there is room for it and marking it visited.
+    fn arrive(&self, i: usize, marked: u64, hand: u16, opened: u64) -> State {
+        let (mut marked, mut hand) = (marked, hand.saturating_sub(self.graph.snare(i)));
+        if let Some(bit) = self.marks[i] {
+            if marked & (1 << bit) == 0 {
+                if !self.graph.has_key(i) {
+                    marked |= 1 << bit;
+                } else if self.rules.capacity.is_none_or(|c| hand < c) {
+                    marked |= 1 << bit;
+                    hand += 1;
+                }
+            }
+        }
+        (i, marked, hand, opened)
+    }
+
+    fn is_goal(&self, state: &State) -> bool {
+        self.ends[state.0] && state.1 & self.all_required == self.all_required
+    }
+}
+
+/// Shortest walk from `f1` that visits every field in `required` and ends on one of `ends`.
+///
+/// Keys are picked up and spent on doors the same way as in `has_path`, following `rules`, but unlike
+/// there a walk may go through the same field or transition more than once, which is usually needed
+/// to reach every required field. A required field with a key counts as visited once its key is
+/// picked up. Snares take keys in hand as in `has_path`. The search runs over states of marked fields
+/// and open doors kept in bitmasks, so it returns an error if there are more than 64 fields with keys
+/// or required, or more than 64 doors that stay open. It counts every move the same and doesn't tell
+/// which bridges were crossed, so it returns an error for traps and bridges too.
+pub fn min_tour(f1: Field, required: Vec<Field>, ends: Vec<Field>, rules: Rules) -> Result<Option<Path>, String> {
+    let graph = Graph::new(f1);
+    graph.refuse_hazards("A tour", |h| matches!(h, Hazard::TRAP(_) | Hazard::BRIDGE))?;
+    let mut required_fields = Vec::new();
+    for f in &required {
+        match graph.index_of(f) {
+            Some(i) => required_fields.push(i),
+            None => return Ok(None),
+        }
+    }
+    let mut marks = vec![None; graph.len()];
+    let mut count = 0;
+    for i in (0..graph.len()).filter(|i| graph.has_key(*i)) {
+        marks[i] = Some(count);
+        count += 1;
+    }
+    let mut all_required = 0u64;
+    for i in required_fields {
+        let bit = *marks[i].get_or_insert_with(|| {
+            count += 1;
+            count - 1
+        });
+        if bit < 64 {
+            all_required |= 1 << bit;
+        }
+    }
+    if count > 64 {
+        return Err(format!("A tour can keep track of at most 64 keys and required fields, this one has {}.", count));
+    }
+    let mut doors = HashMap::new();
+    if rules.doors_stay_open {
+        for i in 0..graph.len() {
+            for e in graph.edges(i).iter().filter(|e| e.doors) {
+                let next = doors.len() as u32;
+                doors.entry((i.min(e.to), i.max(e.to))).or_insert(next);
+            }
+        }
+        if doors.len() > 64 {
+            return Err(format!("A tour can keep at most 64 doors open, this one has {}.", doors.len()));
+        }
+    }
+    let mut end_fields = vec![false; graph.len()];
+    ends.iter().filter_map(|f| graph.index_of(f)).for_each(|i| end_fields[i] = true);
+    let tour = Tour { graph, rules, marks, all_required, doors, ends: end_fields };
+
+    let start = tour.arrive(0, 0, 0, 0);
+    let mut parents: HashMap<State, (State, Direction)> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+    parents.insert(start, (start, Direction::PORTAL));
+    let mut found: Option<State> = None;
+    while let Some(state) = queue.pop_front() {
+        if tour.is_goal(&state) {
+            found = Some(state);
+            break;
+        }
+        let (i, marked, hand, opened) = state;
+        for e in tour.graph.edges(i) {
+            let (mut hand, mut opened) = (hand, opened);
+            if e.doors {
+                let door = tour.doors.get(&(i.min(e.to), i.max(e.to)));
+                if door.is_none_or(|b| opened & (1 << b) == 0) {
+                    match hand.checked_sub(1) {
+                        Some(h) => hand = h,
+                        None => continue,
+                    }
+                    if let Some(b) = door {
+                        opened |= 1 << b;
+                    }
+                }
+            }
+            let next = tour.arrive(e.to, marked, hand, opened);
+            if parents.contains_key(&next) {
+                continue;
+            }
+            parents.insert(next, (state, e.direction));
+            queue.push_back(next);
+        }
+    }
+
+    let Some(mut state) = found else {
+        return Ok(None);
+    };
+    let mut steps = Vec::new();
+    while state != start {
+        let (parent, d) = parents[&state];
+        steps.push(d);
+        state = parent;
+    }
+    steps.reverse();
+    Ok(Some(Path::from_steps(steps)))
+}
+
+/// Shortest walk from the start of the maze that picks up every key and leaves through an exit.
+pub fn min_key_tour(maze: &Maze, rules: Rules) -> Result<Option<Path>, String> {
+    let keys = maze.fields().iter().filter(|f| f.lock().unwrap().has_key()).map(Arc::clone).collect();
+    min_tour(maze.start(), keys, maze.ends(), rules)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::maze::{Direction, Field, Rules, SimpleField, Transition};
+
+    use super::min_tour;
+
+    fn field(x: u8, y: u8, key: bool, end: bool) -> Field {
+        Arc::new(Mutex::new(SimpleField::new(x, y, key, end)))
+    }
+
+    fn tie(f1: &Field, f2: &Field, direction: Direction, doors: bool) {
+        Transition::new(doors, &direction, Arc::clone(f1), Arc::clone(f2));
+        Transition::new(doors, &direction.get_opposite(), Arc::clone(f2), Arc::clone(f1));
+    }
+
+    #[test]
+    fn collects_every_key() {
+        // (0,1) k   (1,1) k
+        //   |         |
+        // (0,0) --- (1,0) -|- (2,0) end
+        let a = field(0, 0, false, false);
+        let b = field(1, 0, false, false);
+        let c = field(2, 0, false, true);
+        let k1 = field(0, 1, true, false);
+        let k2 = field(1, 1, true, false);
+        tie(&a, &b, Direction::EAST, false);
+        tie(&b, &c, Direction::EAST, true);
+        tie(&a, &k1, Direction::SOUTH, false);
+        tie(&b, &k2, Direction::SOUTH, false);
+
+        let keys = vec![Arc::clone(&k1), Arc::clone(&k2)];
+        let p = min_tour(Arc::clone(&a), keys.clone(), vec![Arc::clone(&c)], Rules::default()).unwrap().unwrap();
+        assert_eq!(p.to_string(), "SOUTH -> NORTH -> EAST -> SOUTH -> NORTH -> EAST");
+        // With room for a single key the second one can't be picked up before the doors take the first.
+        let rules = Rules { capacity: Some(1), ..Rules::default() };
+        assert!(min_tour(Arc::clone(&a), keys.clone(), vec![Arc::clone(&c)], rules).unwrap().is_none());
+        let rules = Rules { capacity: Some(2), ..Rules::default() };
+        assert_eq!(min_tour(Arc::clone(&a), keys, vec![Arc::clone(&c)], rules).unwrap().unwrap().cost(), 6);
+
+        // Without anything required it is a plain shortest path, which still needs one key.
+        let p = min_tour(Arc::clone(&a), Vec::new(), vec![Arc::clone(&c)], Rules::default()).unwrap().unwrap();
+        assert_eq!(p.cost(), 4);
+
+        // A required field that can't be reached means there is no tour.
+        let lonely = field(5, 5, false, false);
+        assert!(min_tour(Arc::clone(&a), vec![lonely], vec![Arc::clone(&c)], Rules::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn doors_stay_open() {
+        // (0,0) k, end -|- (1,0): there and back through the same doors with a single key.
+        let a = field(0, 0, true, true);
+        let b = field(1, 0, false, false);
+        tie(&a, &b, Direction::EAST, true);
+        let required = vec![Arc::clone(&b)];
+        assert!(min_tour(Arc::clone(&a), required.clone(), vec![Arc::clone(&a)], Rules::default()).unwrap().is_none());
+        let rules = Rules { doors_stay_open: true, ..Rules::default() };
+        let p = min_tour(Arc::clone(&a), required, vec![Arc::clone(&a)], rules).unwrap().unwrap();
+        assert_eq!(p.to_string(), "EAST -> WEST");
+    }
+
+    #[test]
+    fn too_many_keys() {
+        let fields: Vec<Field> = (0..65).map(|x| field(x, 0, true, x == 64)).collect();
+        fields.windows(2).for_each(|w| tie(&w[0], &w[1], Direction::EAST, false));
+        assert!(min_tour(Arc::clone(&fields[0]), Vec::new(), vec![Arc::clone(&fields[64])], Rules::default()).is_err());
+    }
+}
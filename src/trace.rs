@@ -0,0 +1,255 @@
+use core::fmt;
+use crate::{maze::{Direction, Maze, Position, DIRECTIONS}, topology::Grid};
+
+/// One step of the key-aware search, as recorded by `has_path_trace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Stepped on the field.
+    ENTER(Position),
+    /// Picked up the key lying on the field.
+    KEY(Position),
+    /// A snare on the field took this many keys in hand.
+    SNARE(Position, u16),
+    /// Spent a key on the doors leading from the field in the direction.
+    DOOR(Position, Direction),
+    /// Stepped back off the field, undoing everything done since entering it.
+    BACKTRACK(Position),
+    /// Reached the target with a path of this many steps.
+    FOUND(usize),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::ENTER((x, y, z)) => write!(f, "ENTER {} {} {}", x, y, z),
+            Event::KEY((x, y, z)) => write!(f, "KEY {} {} {}", x, y, z),
+            Event::SNARE((x, y, z), n) => write!(f, "SNARE {} {} {} {}", x, y, z, n),
+            Event::DOOR((x, y, z), d) => write!(f, "DOOR {} {} {} {:?}", x, y, z, d),
+            Event::BACKTRACK((x, y, z)) => write!(f, "BACKTRACK {} {} {}", x, y, z),
+            Event::FOUND(cost) => write!(f, "FOUND {}", cost),
+        }
+    }
+}
+
+fn parse_position(words: &[&str]) -> Option<Position> {
+    match words {
+        [x, y, z, ..] => Some((x.parse().ok()?, y.parse().ok()?, z.parse().ok()?)),
+        _ => None,
+    }
+}
+
+fn parse_event(line: &str) -> Option<Event> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (kind, args) = words.split_first()?;
+    let event = match *kind {
+        "ENTER" if args.len() == 3 => Event::ENTER(parse_position(args)?),
+        "KEY" if args.len() == 3 => Event::KEY(parse_position(args)?),
+        "SNARE" if args.len() == 4 => Event::SNARE(parse_position(args)?, args[3].parse().ok()?),
+        "DOOR" if args.len() == 4 => Event::DOOR(parse_position(args)?, DIRECTIONS.into_iter().find(|d| format!("{:?}", d) == args[3])?),
+        "BACKTRACK" if args.len() == 3 => Event::BACKTRACK(parse_position(args)?),
+        "FOUND" if args.len() == 1 => Event::FOUND(args[0].parse().ok()?),
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// Writes the events one per line, in the format `read_trace` reads.
+pub fn write_trace(events: &[Event]) -> String {
+    events.iter().map(|e| format!("{}\n", e)).collect()
+}
+
+pub fn read_trace(content: &str) -> Result<Vec<Event>, String> {
+    content.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_event(line).ok_or(format!("Invalid trace event on line {}: {}", i + 1, line.trim())))
+        .collect()
+}
+
+// A field on the path being explored: whether its key was picked up, how many keys a snare on it
+// took and whether doors were opened to get there.
+struct Step {
+    position: Position,
+    key: bool,
+    lost: usize,
+    door: bool,
+}
+
+/// Animation of a recorded search: every event gives a frame showing the path being explored.
+///
+/// On the frames `@` is the field the search is on, `*` the rest of its path, `k` keys that
+/// aren't picked up on the path and `E` exits. On square grids doors are drawn as `:` and `...`.
+pub struct Replay<'a> {
+    maze: &'a Maze,
+    events: &'a [Event],
+    next: usize,
+    path: Vec<Step>,
+    hand: usize,
+    door_opened: bool,
+    best: Option<usize>,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(maze: &'a Maze, events: &'a [Event]) -> Self {
+        Replay { maze, events, next: 0, path: Vec::new(), hand: 0, door_opened: false, best: None }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::ENTER(position) => {
+                self.path.push(Step { position, key: false, lost: 0, door: self.door_opened });
+                self.door_opened = false;
+            },
+            Event::KEY(_) => {
+                if let Some(step) = self.path.last_mut() {
+                    step.key = true;
+                }
+                self.hand += 1;
+            },
+            Event::SNARE(_, n) => {
+                if let Some(step) = self.path.last_mut() {
+                    step.lost = usize::from(n);
+                }
+                self.hand = self.hand.saturating_sub(usize::from(n));
+            },
+            Event::DOOR(_, _) => {
+                self.door_opened = true;
+                self.hand = self.hand.saturating_sub(1);
+            },
+            Event::BACKTRACK(_) => {
+                if let Some(step) = self.path.pop() {
+                    if step.key {
+                        self.hand = self.hand.saturating_sub(1);
+                    }
+                    self.hand += step.lost;
+                    if step.door {
+                        self.hand += 1;
+                    }
+                }
+            },
+            Event::FOUND(cost) => self.best = Some(self.best.map_or(cost, |b| b.min(cost))),
+        }
+    }
+
+    fn cell(&self, position: Position) -> char {
+        if self.path.last().is_some_and(|s| s.position == position) {
+            return '@';
+        }
+        if self.path.iter().any(|s| s.position == position) {
+            return '*';
+        }
+        match self.maze.get(position) {
+            Some(f) if f.lock().unwrap().has_key() => 'k',
+            Some(f) if f.lock().unwrap().is_end() => 'E',
+            _ => ' ',
+        }
+    }
+
+    fn draw(&self) -> String {
+        draw(self.maze, |p| self.cell(p))
+    }
+}
+
+impl Iterator for Replay<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let event = *self.events.get(self.next)?;
+        self.next += 1;
+        self.apply(event);
+        let best = self.best.map_or(String::from("-"), |b| b.to_string());
+        Some(format!("{}Step {}/{}: {}\nKeys: {}  Depth: {}  Best: {}\n", self.draw(), self.next, self.events.len(), event, self.hand, self.path.len().saturating_sub(1), best))
+    }
+}
+
+// Whether the field can be left in the direction, and if so whether there are doors on the way.
+fn transition(maze: &Maze, position: Position, direction: Direction) -> Option<bool> {
+    let t = maze.get(position)?.lock().unwrap().get_transition(direction)?;
+    let doors = t.lock().unwrap().has_doors();
+    Some(doors)
+}
+
+/// Draws every floor of the maze with the character `cell` gives for every field. Square grids get
+/// walls drawn between the fields, with doors as `:` and `...`, hex grids only the odd rows shifted.
+pub(crate) fn draw(maze: &Maze, cell: impl Fn(Position) -> char) -> String {
+    let (width, height, floors) = maze.size();
+    let mut out = String::new();
+    for z in 0..floors {
+        if z > 0 {
+            out.push('\n');
+        }
+        if maze.grid() == Grid::HEX {
+            for y in 0..height {
+                out.push_str(if y % 2 == 1 { "  " } else { "" });
+                (0..width).for_each(|x| out.push_str(&format!(" {}  ", cell((x, y, z)))));
+                out.push('\n');
+            }
+            continue;
+        }
+        out.push_str(&format!("+{}\n", "---+".repeat(usize::from(width))));
+        for y in 0..height {
+            out.push('|');
+            for x in 0..width {
+                let wall = match transition(maze, (x, y, z), Direction::EAST) {
+                    Some(true) => ':',
+                    Some(false) => ' ',
+                    None => '|',
+                };
+                out.push_str(&format!(" {} {}", cell((x, y, z)), wall));
+            }
+            out.push_str("\n+");
+            for x in 0..width {
+                out.push_str(match transition(maze, (x, y, z), Direction::SOUTH) {
+                    Some(true) => "...+",
+                    Some(false) => "   +",
+                    None => "---+",
+                });
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{maze::{has_path_trace, Direction, Hazard, Rules}, reader::read_maze};
+
+    use super::{read_trace, write_trace, Event, Replay};
+
+    #[test]
+    fn record_and_replay() {
+        // (0,0) k --- (1,0) -|- (2,0) end
+        let mut maze = read_maze("SQUARE 3 1\n0100 0000 1100\n1100 0100 0000\n1000 0000 0011\n").unwrap();
+        let mut events = Vec::new();
+        let p = has_path_trace(maze.start(), Arc::clone(&maze.ends()[0]), Rules::default(), &mut events);
+        assert_eq!(p.unwrap().cost(), 2);
+        assert_eq!(events[..3], [Event::ENTER((0, 0, 0)), Event::KEY((0, 0, 0)), Event::ENTER((1, 0, 0))]);
+        assert!(events.contains(&Event::DOOR((1, 0, 0), Direction::EAST)));
+        assert!(events.contains(&Event::FOUND(2)));
+        // Every field entered is left again.
+        let entered = events.iter().filter(|e| matches!(e, Event::ENTER(_))).count();
+        assert_eq!(entered, events.iter().filter(|e| matches!(e, Event::BACKTRACK(_))).count());
+
+        let content = write_trace(&events);
+        assert_eq!(read_trace(&content).unwrap(), events);
+        assert!(read_trace("ENTER 1 2\n").is_err());
+
+        let frames: Vec<String> = Replay::new(&maze, &events).collect();
+        assert_eq!(frames.len(), events.len());
+        assert!(frames[2].starts_with("+---+---+---+\n| *   @ : E |\n+---+---+---+\nStep 3/"));
+
+        // A snare on (1,0) takes the key before the doors, and gives it back when the search steps off it.
+        assert!(maze.set_hazard((1, 0, 0), Some(Hazard::SNARE(1))));
+        let mut events = Vec::new();
+        assert!(has_path_trace(maze.start(), Arc::clone(&maze.ends()[0]), Rules::default(), &mut events).is_none());
+        assert_eq!(events[..4], [Event::ENTER((0, 0, 0)), Event::KEY((0, 0, 0)), Event::ENTER((1, 0, 0)), Event::SNARE((1, 0, 0), 1)]);
+        assert_eq!(read_trace(&write_trace(&events)).unwrap(), events);
+        let frames: Vec<String> = Replay::new(&maze, &events).collect();
+        assert!(frames[2].contains("Keys: 1 "));
+        assert!(frames[3].contains("Keys: 0 "));
+        let i = events.iter().position(|e| *e == Event::BACKTRACK((1, 0, 0))).unwrap();
+        assert!(frames[i].contains("Keys: 1 "));
+    }
+}